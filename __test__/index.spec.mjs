@@ -1,7 +1,132 @@
+import { randomBytes } from 'crypto'
+import { mkdtempSync, readFileSync } from 'fs'
+import { tmpdir } from 'os'
+import { join } from 'path'
+import { Readable } from 'stream'
+import { gzipSync } from 'zlib'
+
 import test from 'ava'
 
-import { sum } from '../index.js'
+import { extractTarballBuffer, extractTarballStream } from '../index.js'
+
+/** A ustar archive of regular files, without dependencies on tar packages. */
+function tar(files) {
+  const blocks = []
+  for (const [name, contents] of Object.entries(files)) {
+    const header = Buffer.alloc(512)
+    header.write(name, 0)
+    header.write('0000644\0', 100)
+    header.write('0000000\0', 108)
+    header.write('0000000\0', 116)
+    header.write(`${contents.length.toString(8).padStart(11, '0')}\0`, 124)
+    header.write('00000000000\0', 136)
+    header.write('        ', 148)
+    header.write('0', 156)
+    header.write('ustar\0', 257)
+    header.write('00', 263)
+    const checksum = header.reduce((sum, byte) => sum + byte, 0)
+    header.write(`${checksum.toString(8).padStart(6, '0')}\0 `, 148)
+    blocks.push(header, contents, Buffer.alloc((512 - (contents.length % 512)) % 512))
+  }
+  blocks.push(Buffer.alloc(1024))
+  return Buffer.concat(blocks)
+}
+
+function chunks(data, size) {
+  const parts = []
+  for (let start = 0; start < data.length; start += size) {
+    parts.push(data.subarray(start, start + size))
+  }
+  return parts
+}
+
+function tempStore() {
+  return mkdtempSync(join(tmpdir(), 'tarball-fetcher-'))
+}
+
+test('extractTarballBuffer extracts a tarball in memory', async (t) => {
+  const tarball = gzipSync(tar({ 'package/index.js': Buffer.from('module.exports = 1\n') }))
+  const extracted = await extractTarballBuffer(tarball, undefined, tempStore())
+  t.is(readFileSync(extracted.files['index.js'].location, 'utf8'), 'module.exports = 1\n')
+  t.true(extracted.integrity.startsWith('sha512-'))
+})
+
+test('extractTarballStream keeps up with streams of many chunks', async (t) => {
+  // Random data doesn't compress, so this is about 256 chunks, all emitted at
+  // once, faster than they're extracted.
+  const contents = randomBytes(1024 * 1024)
+  const tarball = gzipSync(tar({ 'package/random.bin': contents }))
+  const stream = new Readable({
+    read() {
+      for (const chunk of chunks(tarball, 4096)) this.push(chunk)
+      this.push(null)
+    },
+  })
+  let pauses = 0
+  const pause = stream.pause
+  stream.pause = function () {
+    pauses++
+    return pause.call(this)
+  }
+
+  const extracted = await extractTarballStream(stream, undefined, tempStore())
+  t.true(readFileSync(extracted.files['random.bin'].location).equals(contents))
+  t.true(pauses > 0)
+  t.true(stream.readableEnded)
+  t.false(stream.isPaused())
+})
+
+test('extractTarballStream fails with the error of the stream', async (t) => {
+  const tarball = gzipSync(tar({ 'package/random.bin': randomBytes(64 * 1024) }))
+  let started = false
+  const stream = new Readable({
+    read() {
+      if (started) return
+      started = true
+      this.push(tarball.subarray(0, 1024))
+      setImmediate(() => this.destroy(new Error('connection reset')))
+    },
+  })
+
+  const error = await t.throwsAsync(extractTarballStream(stream, undefined, tempStore()))
+  t.is(error.code, 'ERR_PNPM_IO')
+  t.regex(error.message, /connection reset/)
+})
+
+test('extractTarballStream fails on streams destroyed before they end', async (t) => {
+  const tarball = gzipSync(tar({ 'package/random.bin': randomBytes(64 * 1024) }))
+  let started = false
+  const stream = new Readable({
+    read() {
+      if (started) return
+      started = true
+      this.push(tarball.subarray(0, 1024))
+      setImmediate(() => this.destroy())
+    },
+  })
+
+  const error = await t.throwsAsync(extractTarballStream(stream, undefined, tempStore()))
+  t.is(error.code, 'ERR_PNPM_IO')
+  t.regex(error.message, /closed before it ended/)
+})
+
+test('extractTarballStream fails on streams that are already over', async (t) => {
+  const ended = Readable.from([])
+  ended.resume()
+  await new Promise((resolve) => ended.on('end', resolve))
+  const destroyed = new Readable({ read() {} })
+  destroyed.destroy()
+  const errored = new Readable({ read() {} })
+  errored.on('error', () => {})
+  errored.destroy(new Error('connection reset'))
 
-test('sum from native', (t) => {
-  t.is(sum(1, 2), 3)
+  for (const [stream, message] of [
+    [ended, /already ended/],
+    [destroyed, /closed before it ended/],
+    [errored, /connection reset/],
+  ]) {
+    const error = await t.throwsAsync(extractTarballStream(stream, undefined, tempStore()))
+    t.is(error.code, 'ERR_PNPM_IO')
+    t.regex(error.message, message)
+  }
 })
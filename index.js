@@ -252,6 +252,9 @@ if (!nativeBinding) {
   throw new Error(`Failed to load native binding`)
 }
 
-const { fetchTarball } = nativeBinding
+const { fetchTarball, extractTarballBuffer, extractTarballStream, TarballFetcher } = nativeBinding
 
 module.exports.fetchTarball = fetchTarball
+module.exports.extractTarballBuffer = extractTarballBuffer
+module.exports.extractTarballStream = extractTarballStream
+module.exports.TarballFetcher = TarballFetcher
@@ -0,0 +1,139 @@
+use base64::Engine;
+use reqwest::{header::HeaderValue, Url};
+use std::collections::HashMap;
+
+use crate::FetchError;
+
+/// Credentials for one registry, in the shapes `.npmrc` supports.
+#[napi(object)]
+#[derive(Clone, Default)]
+pub struct RegistryAuth {
+  /// Sent as a bearer token (`_authToken`).
+  pub token: Option<String>,
+  /// Base64 encoded `username:password`, sent as basic auth (`_auth`).
+  pub auth: Option<String>,
+  /// Sent as basic auth together with `password`.
+  pub username: Option<String>,
+  pub password: Option<String>,
+}
+
+impl RegistryAuth {
+  fn header_value(&self) -> Result<Option<HeaderValue>, FetchError> {
+    let value = if let Some(token) = &self.token {
+      format!("Bearer {}", token)
+    } else if let Some(auth) = &self.auth {
+      format!("Basic {}", auth)
+    } else if let (Some(username), Some(password)) = (&self.username, &self.password) {
+      let credentials = format!("{}:{}", username, password);
+      format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(credentials)
+      )
+    } else {
+      return Ok(None);
+    };
+    let mut header = HeaderValue::from_str(&value).map_err(|_| {
+      FetchError::InvalidInput("Registry credentials contain invalid characters".to_string())
+    })?;
+    header.set_sensitive(true);
+    Ok(Some(header))
+  }
+}
+
+/// The `Authorization` headers to send, keyed by registry URL prefix.
+#[derive(Clone, Debug, Default)]
+pub struct AuthConfig {
+  // Sorted longest prefix first, so the most specific registry wins.
+  headers: Vec<(String, HeaderValue)>,
+}
+
+impl AuthConfig {
+  pub fn from_registries(registries: &HashMap<String, RegistryAuth>) -> Result<Self, FetchError> {
+    let mut config = AuthConfig::default();
+    for (registry, auth) in registries {
+      config.insert(registry, auth)?;
+    }
+    Ok(config)
+  }
+
+  /// Adds credentials for every URL under `registry`, which may be a full URL
+  /// (`https://npm.example.com/`) or a protocol-relative one (`//npm.example.com/`).
+  pub fn insert(&mut self, registry: &str, auth: &RegistryAuth) -> Result<(), FetchError> {
+    if let Some(header) = auth.header_value()? {
+      let prefix = registry_prefix(registry);
+      self.headers.retain(|(existing, _)| *existing != prefix);
+      self.headers.push((prefix, header));
+      self
+        .headers
+        .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+    }
+    Ok(())
+  }
+
+  /// The `Authorization` header for a request to `url`, if any registry covers it.
+  pub fn header_for(&self, url: &Url) -> Option<HeaderValue> {
+    let target = nerf_dart(url);
+    self
+      .headers
+      .iter()
+      .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+      .map(|(_, header)| header.clone())
+  }
+}
+
+/// The protocol-relative form of a URL that npm keys credentials by,
+/// e.g. `//npm.example.com:8080/path/`.
+pub fn nerf_dart(url: &Url) -> String {
+  let mut nerfed = format!("//{}", url.host_str().unwrap_or_default());
+  if let Some(port) = url.port() {
+    nerfed.push_str(&format!(":{}", port));
+  }
+  nerfed.push_str(url.path());
+  nerfed
+}
+
+fn registry_prefix(registry: &str) -> String {
+  let mut prefix = match Url::parse(registry) {
+    Ok(url) if url.has_host() => nerf_dart(&url),
+    _ => format!("//{}", registry.trim_start_matches("//")),
+  };
+  if !prefix.ends_with('/') {
+    prefix.push('/');
+  }
+  prefix
+}
+
+#[test]
+fn picks_the_most_specific_registry() {
+  let mut config = AuthConfig::default();
+  let token = |token: &str| RegistryAuth {
+    token: Some(token.to_string()),
+    ..Default::default()
+  };
+  config.insert("//npm.example.com/", &token("root")).unwrap();
+  config
+    .insert("https://npm.example.com/private", &token("private"))
+    .unwrap();
+
+  let header = |url: &str| config.header_for(&Url::parse(url).unwrap());
+  assert_eq!(
+    header("https://npm.example.com/private/foo/-/foo-1.0.0.tgz").unwrap(),
+    "Bearer private"
+  );
+  assert_eq!(
+    header("https://npm.example.com/foo/-/foo-1.0.0.tgz").unwrap(),
+    "Bearer root"
+  );
+  assert!(header("https://npm.example.com.evil.com/foo.tgz").is_none());
+  assert!(header("https://npm.example.com:8443/foo.tgz").is_none());
+}
+
+#[test]
+fn encodes_basic_auth() {
+  let auth = RegistryAuth {
+    username: Some("user".to_string()),
+    password: Some("pass".to_string()),
+    ..Default::default()
+  };
+  assert_eq!(auth.header_value().unwrap().unwrap(), "Basic dXNlcjpwYXNz");
+}
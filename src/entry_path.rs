@@ -0,0 +1,156 @@
+use std::{
+  error::Error,
+  fmt,
+  path::{Component, Path},
+};
+use unicode_normalization::UnicodeNormalization;
+
+/// A tarball entry whose path would end up outside of the package.
+#[derive(Debug)]
+pub struct UnsafeEntryPath {
+  /// The path as it appears in the tarball.
+  pub entry: String,
+}
+
+impl fmt::Display for UnsafeEntryPath {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Tarball entry {:?} points outside of the package directory",
+      self.entry
+    )
+  }
+}
+
+impl Error for UnsafeEntryPath {}
+
+/// A tarball entry whose name isn't valid UTF-8, with strict entry names on.
+#[derive(Debug)]
+pub struct InvalidEntryName {
+  /// The name with invalid bytes replaced by U+FFFD.
+  pub entry: String,
+}
+
+impl fmt::Display for InvalidEntryName {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Tarball entry {:?} is not valid UTF-8", self.entry)
+  }
+}
+
+impl Error for InvalidEntryName {}
+
+/// Decodes the raw name of a tarball entry into NFC, so that a package yields
+/// the same file names whichever way its author's system composes accents.
+///
+/// Names that aren't valid UTF-8 are rejected when `strict`, otherwise invalid
+/// bytes are replaced and a warning is returned along with the name.
+pub fn decode_entry_name(
+  raw: &[u8],
+  strict: bool,
+) -> Result<(String, Option<String>), InvalidEntryName> {
+  match std::str::from_utf8(raw) {
+    Ok(name) => Ok((name.nfc().collect(), None)),
+    Err(_) => {
+      let entry: String = String::from_utf8_lossy(raw).nfc().collect();
+      if strict {
+        return Err(InvalidEntryName { entry });
+      }
+      let warning = format!(
+        "Tarball entry {:?} is not valid UTF-8, invalid bytes were replaced",
+        entry
+      );
+      Ok((entry, Some(warning)))
+    }
+  }
+}
+
+/// Turns the path of a tarball entry into the path of the file inside the
+/// package, dropping the top-level directory npm packs everything into
+/// (`package/` for tarballs made by `npm pack`, but it can be named anything).
+///
+/// `.` components are dropped. Parent directories, roots and drive prefixes,
+/// as well as components that could be read as one of those on Windows
+/// (`C:`, `a\..\b`), are rejected. Components are joined with `/` on every
+/// platform, like pnpm does.
+pub fn package_path(entry: &str) -> Result<String, UnsafeEntryPath> {
+  let unsafe_entry = || UnsafeEntryPath {
+    entry: entry.to_string(),
+  };
+
+  let mut components = Path::new(entry)
+    .components()
+    .filter(|component| *component != Component::CurDir);
+  match components.next() {
+    Some(Component::Normal(_)) | None => {}
+    Some(_) => return Err(unsafe_entry()),
+  }
+
+  let mut names = Vec::new();
+  for component in components {
+    let Component::Normal(name) = component else {
+      return Err(unsafe_entry());
+    };
+    let name = name.to_str().ok_or_else(unsafe_entry)?;
+    if is_windows_escape(name) {
+      return Err(unsafe_entry());
+    }
+    names.push(name);
+  }
+  Ok(names.join("/"))
+}
+
+/// Whether a name that is fine on unix would be read as a root, a drive or a
+/// parent directory on Windows. Other characters that Windows doesn't allow
+/// in names, like `:` in `a:b.js`, can't lead outside of the package.
+fn is_windows_escape(name: &str) -> bool {
+  let first = name.split('\\').next().unwrap_or_default();
+  let is_drive =
+    first.len() == 2 && first.as_bytes()[0].is_ascii_alphabetic() && first.ends_with(':');
+  is_drive || name.starts_with('\\') || name.split('\\').any(|segment| segment == "..")
+}
+
+#[test]
+fn package_paths_drop_the_top_level_directory() {
+  let path = |entry: &str| package_path(entry).unwrap();
+  assert_eq!(path("package/lib/index.js"), "lib/index.js");
+  assert_eq!(path("./node/./index.js"), "index.js");
+  assert_eq!(path("package/"), "");
+  assert_eq!(path("package/lib/a:b.js"), "lib/a:b.js");
+  assert_eq!(path("package/what?*|\"<>.js"), "what?*|\"<>.js");
+  assert_eq!(path("package/a\\b..c"), "a\\b..c");
+}
+
+#[test]
+fn package_paths_outside_of_the_package_are_rejected() {
+  for entry in [
+    "package/../../etc/passwd",
+    "../package/index.js",
+    "/etc/passwd",
+    "package/C:/Windows/win.ini",
+    "package/..\\..\\index.js",
+    "package/a\\..\\..\\index.js",
+    "package/C:\\Windows\\win.ini",
+    "package/\\Windows\\win.ini",
+  ] {
+    let error = package_path(entry).unwrap_err();
+    assert_eq!(error.entry, entry);
+  }
+}
+
+#[test]
+fn entry_names_are_decoded_into_nfc() {
+  let (name, warning) = decode_entry_name("package/cafe\u{301}.js".as_bytes(), true).unwrap();
+  assert_eq!(name, "package/caf\u{e9}.js");
+  assert_eq!(warning, None);
+}
+
+#[test]
+fn entry_names_that_are_not_utf8_are_replaced_unless_strict() {
+  let raw = b"package/caf\xe9.js";
+  let (name, warning) = decode_entry_name(raw, false).unwrap();
+  assert_eq!(name, "package/caf\u{fffd}.js");
+  assert!(warning.unwrap().contains("not valid UTF-8"));
+
+  let error = decode_entry_name(raw, true).unwrap_err();
+  assert_eq!(error.entry, "package/caf\u{fffd}.js");
+}
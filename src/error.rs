@@ -0,0 +1,495 @@
+use napi::{
+  bindgen_prelude::{ToNapiValue, TypeName},
+  sys, Env, JsObject, ValueType,
+};
+use reqwest::StatusCode;
+use std::{error::Error, fmt, io};
+use tokio::task;
+
+use crate::{
+  retry, InvalidEntryName, LimitExceeded, RetriesExhausted, TimeoutError, TimeoutPhase,
+  UnsafeEntryPath,
+};
+
+/// The broad class of a non-2xx registry response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HttpErrorKind {
+  NotFound,
+  Unauthorized,
+  Forbidden,
+  ServerError,
+  Other,
+}
+
+impl HttpErrorKind {
+  pub fn from_status(status: StatusCode) -> Self {
+    match status {
+      StatusCode::NOT_FOUND => HttpErrorKind::NotFound,
+      StatusCode::UNAUTHORIZED => HttpErrorKind::Unauthorized,
+      StatusCode::FORBIDDEN => HttpErrorKind::Forbidden,
+      status if status.is_server_error() => HttpErrorKind::ServerError,
+      _ => HttpErrorKind::Other,
+    }
+  }
+}
+
+/// A registry answered with something other than a 2xx status.
+#[derive(Debug)]
+pub struct HttpError {
+  pub kind: HttpErrorKind,
+  pub url: String,
+  pub status: u16,
+}
+
+impl HttpError {
+  pub fn new(url: &str, status: StatusCode) -> Self {
+    HttpError {
+      kind: HttpErrorKind::from_status(status),
+      url: url.to_string(),
+      status: status.as_u16(),
+    }
+  }
+
+  /// pnpm's code for failed requests, e.g. `ERR_PNPM_FETCH_404`.
+  pub fn code(&self) -> String {
+    format!("ERR_PNPM_FETCH_{}", self.status)
+  }
+}
+
+impl fmt::Display for HttpError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let reason = StatusCode::from_u16(self.status)
+      .ok()
+      .and_then(|status| status.canonical_reason())
+      .unwrap_or("Unknown");
+    write!(f, "GET {}: {} - {}", self.url, reason, self.status)
+  }
+}
+
+impl Error for HttpError {}
+
+/// The tarball didn't match the integrity it was expected to have.
+#[derive(Debug)]
+pub struct IntegrityError {
+  /// Where the tarball came from, if it was downloaded.
+  pub url: Option<String>,
+  pub expected: String,
+  /// The hash of what was received, with the algorithm it was checked with.
+  pub actual: String,
+  /// Length of the compressed tarball in bytes.
+  pub size: u64,
+  /// Which download attempt received it, starting at 1.
+  pub attempt: Option<u32>,
+}
+
+impl fmt::Display for IntegrityError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.url {
+      Some(url) => write!(f, "Got unexpected checksum for \"{}\".", url)?,
+      None => write!(f, "Got unexpected checksum.")?,
+    }
+    write!(f, " Wanted \"{}\". Got \"{}\".", self.expected, self.actual)
+  }
+}
+
+impl Error for IntegrityError {}
+
+/// Marks the errors of the gzip decoder, so that they can be told apart from
+/// tar errors once they come out of the archive reader.
+#[derive(Debug)]
+pub struct DecompressError(pub io::Error);
+
+impl fmt::Display for DecompressError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Failed to decompress the tarball: {}", self.0)
+  }
+}
+
+impl Error for DecompressError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    Some(&self.0)
+  }
+}
+
+/// A failure while fetching a tarball, with the url and integrity it was
+/// fetched for.
+#[derive(Debug)]
+pub struct TarballError {
+  pub url: String,
+  pub integrity: Option<String>,
+  pub source: Box<FetchError>,
+}
+
+impl fmt::Display for TarballError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.source.names_url() {
+      self.source.fmt(f)
+    } else {
+      write!(
+        f,
+        "Failed to add tarball from \"{}\" to store: {}",
+        self.url, self.source
+      )
+    }
+  }
+}
+
+impl Error for TarballError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    Some(self.source.as_ref())
+  }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+  /// The request couldn't be sent or the response couldn't be read.
+  Network(reqwest::Error),
+  /// The registry redirected to somewhere that can't be followed.
+  Redirect(String),
+  Http(HttpError),
+  Timeout(TimeoutError),
+  Integrity(IntegrityError),
+  /// The tarball isn't valid gzip.
+  Decompress(io::Error),
+  /// The decompressed tarball isn't a valid tar archive.
+  Tar(io::Error),
+  UnsafeEntryPath(UnsafeEntryPath),
+  InvalidEntryName(InvalidEntryName),
+  LimitExceeded(LimitExceeded),
+  /// Reading or writing files, mostly in the store.
+  Io(io::Error),
+  /// Malformed options, configuration or arguments.
+  InvalidInput(String),
+  /// The package isn't in the store, and the network may not be used.
+  NotInStore,
+  RetriesExhausted(RetriesExhausted),
+  Tarball(TarballError),
+}
+
+impl FetchError {
+  /// A decompression error found outside of the decoder, like a broken gzip
+  /// header, reported like the ones of the decoder.
+  pub fn decompress(error: io::Error) -> Self {
+    FetchError::Decompress(io::Error::new(error.kind(), DecompressError(error)))
+  }
+
+  /// Classifies an error that came out of the tar reader, which passes on the
+  /// errors of the readers underneath.
+  pub fn from_archive(error: io::Error) -> Self {
+    let error = match error.downcast::<LimitExceeded>() {
+      Ok(exceeded) => return FetchError::LimitExceeded(exceeded),
+      Err(error) => error,
+    };
+    let decompressing = error
+      .get_ref()
+      .is_some_and(|inner| inner.is::<DecompressError>());
+    if decompressing {
+      FetchError::Decompress(error)
+    } else {
+      FetchError::Tar(error)
+    }
+  }
+
+  /// Whether asking again might succeed: connection problems, timeouts,
+  /// rate limiting and server errors.
+  pub fn is_retryable(&self) -> bool {
+    match self {
+      FetchError::Network(error) => retry::is_retryable_error(error),
+      FetchError::Http(error) => StatusCode::from_u16(error.status)
+        .map(retry::is_retryable_status)
+        .unwrap_or(false),
+      // A corrupted download may well be intact the next time.
+      FetchError::Timeout(_) | FetchError::Integrity(_) => true,
+      FetchError::Tarball(error) => error.source.is_retryable(),
+      _ => false,
+    }
+  }
+
+  /// Whether the message already says which url failed.
+  fn names_url(&self) -> bool {
+    match self {
+      FetchError::Network(_)
+      | FetchError::Redirect(_)
+      | FetchError::Http(_)
+      | FetchError::Timeout(_)
+      | FetchError::Integrity(_) => true,
+      FetchError::RetriesExhausted(error) => error.source.names_url(),
+      _ => false,
+    }
+  }
+
+  /// The `code` property of the JS error.
+  pub fn code(&self) -> String {
+    let code = match self {
+      FetchError::Network(_) => "ERR_PNPM_NETWORK",
+      FetchError::Redirect(_) => "ERR_PNPM_FETCH_REDIRECT",
+      FetchError::Http(error) => return error.code(),
+      FetchError::Timeout(_) => "ERR_PNPM_FETCH_TIMEOUT",
+      FetchError::Integrity(_) => "ERR_PNPM_TARBALL_INTEGRITY",
+      FetchError::Decompress(_) => "ERR_PNPM_TARBALL_DECOMPRESS",
+      FetchError::Tar(_) => "ERR_PNPM_TARBALL_EXTRACT",
+      FetchError::UnsafeEntryPath(_) => "ERR_PNPM_UNSAFE_TARBALL_ENTRY",
+      FetchError::InvalidEntryName(_) => "ERR_PNPM_INVALID_TARBALL_ENTRY_NAME",
+      FetchError::LimitExceeded(_) => "ERR_PNPM_TARBALL_LIMIT_EXCEEDED",
+      FetchError::Io(_) => "ERR_PNPM_IO",
+      FetchError::InvalidInput(_) => "ERR_PNPM_INVALID_INPUT",
+      FetchError::NotInStore => "ERR_PNPM_NO_OFFLINE_TARBALL",
+      FetchError::RetriesExhausted(error) => return error.source.code(),
+      FetchError::Tarball(error) => return error.source.code(),
+    };
+    code.to_string()
+  }
+
+  fn set_js_properties(&self, object: &mut JsObject) -> napi::Result<()> {
+    match self {
+      FetchError::Http(error) => {
+        object.set_named_property("url", error.url.as_str())?;
+        object.set_named_property("statusCode", error.status)?;
+      }
+      FetchError::Timeout(error) => {
+        object.set_named_property("url", error.url.as_str())?;
+        object.set_named_property("timeoutPhase", error.phase.as_str())?;
+        if let Some(limit) = error.limit {
+          object.set_named_property("timeout", limit.as_millis() as f64)?;
+        }
+      }
+      FetchError::Integrity(error) => {
+        if let Some(url) = &error.url {
+          object.set_named_property("url", url.as_str())?;
+        }
+        object.set_named_property("expected", error.expected.as_str())?;
+        object.set_named_property("actual", error.actual.as_str())?;
+        object.set_named_property("size", error.size as f64)?;
+        if let Some(attempt) = error.attempt {
+          object.set_named_property("attempt", attempt)?;
+        }
+      }
+      FetchError::UnsafeEntryPath(UnsafeEntryPath { entry })
+      | FetchError::InvalidEntryName(InvalidEntryName { entry }) => {
+        object.set_named_property("entry", entry.as_str())?;
+      }
+      FetchError::LimitExceeded(error) => {
+        object.set_named_property("limit", error.limit.option_name())?;
+        object.set_named_property("max", error.max as f64)?;
+        if let Some(entry) = &error.entry {
+          object.set_named_property("entry", entry.as_str())?;
+        }
+      }
+      FetchError::Io(error) => {
+        if let Some(errno) = error.raw_os_error() {
+          object.set_named_property("errno", errno)?;
+        }
+      }
+      FetchError::RetriesExhausted(error) => {
+        error.source.set_js_properties(object)?;
+        object.set_named_property("attempts", error.attempts)?;
+      }
+      FetchError::Tarball(error) => {
+        error.source.set_js_properties(object)?;
+        object.set_named_property("url", error.url.as_str())?;
+        if let Some(integrity) = &error.integrity {
+          object.set_named_property("integrity", integrity.as_str())?;
+        }
+      }
+      FetchError::Network(_)
+      | FetchError::Redirect(_)
+      | FetchError::Decompress(_)
+      | FetchError::Tar(_)
+      | FetchError::InvalidInput(_)
+      | FetchError::NotInStore => {}
+    }
+    Ok(())
+  }
+
+  /// Builds the JS `Error` this is rejected with: the message, a stable
+  /// `code` and whatever details the variant carries.
+  pub fn to_js_error(&self, env: &Env) -> napi::Result<JsObject> {
+    let mut object = env.create_error(napi::Error::from_reason(self.to_string()))?;
+    object.set_named_property("code", self.code())?;
+    self.set_js_properties(&mut object)?;
+    Ok(object)
+  }
+}
+
+impl fmt::Display for FetchError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      FetchError::Network(error) => error.fmt(f),
+      FetchError::Redirect(message) | FetchError::InvalidInput(message) => message.fmt(f),
+      FetchError::Http(error) => error.fmt(f),
+      FetchError::Timeout(error) => error.fmt(f),
+      FetchError::Integrity(error) => error.fmt(f),
+      FetchError::Decompress(error) => error.fmt(f),
+      FetchError::Tar(error) => write!(f, "Failed to extract the tarball: {}", error),
+      FetchError::UnsafeEntryPath(error) => error.fmt(f),
+      FetchError::InvalidEntryName(error) => error.fmt(f),
+      FetchError::LimitExceeded(error) => error.fmt(f),
+      FetchError::Io(error) => error.fmt(f),
+      FetchError::NotInStore => write!(
+        f,
+        "The package is not in the store and cannot be downloaded in offline mode"
+      ),
+      FetchError::RetriesExhausted(error) => error.fmt(f),
+      FetchError::Tarball(error) => error.fmt(f),
+    }
+  }
+}
+
+impl Error for FetchError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      FetchError::Network(error) => Some(error),
+      FetchError::Decompress(error) | FetchError::Tar(error) | FetchError::Io(error) => Some(error),
+      FetchError::RetriesExhausted(error) => Some(error),
+      FetchError::Tarball(error) => Some(error),
+      _ => None,
+    }
+  }
+}
+
+impl From<HttpError> for FetchError {
+  fn from(error: HttpError) -> Self {
+    FetchError::Http(error)
+  }
+}
+
+impl From<TimeoutError> for FetchError {
+  fn from(error: TimeoutError) -> Self {
+    FetchError::Timeout(error)
+  }
+}
+
+impl From<RetriesExhausted> for FetchError {
+  fn from(error: RetriesExhausted) -> Self {
+    FetchError::RetriesExhausted(error)
+  }
+}
+
+impl From<UnsafeEntryPath> for FetchError {
+  fn from(error: UnsafeEntryPath) -> Self {
+    FetchError::UnsafeEntryPath(error)
+  }
+}
+
+impl From<InvalidEntryName> for FetchError {
+  fn from(error: InvalidEntryName) -> Self {
+    FetchError::InvalidEntryName(error)
+  }
+}
+
+impl From<LimitExceeded> for FetchError {
+  fn from(error: LimitExceeded) -> Self {
+    FetchError::LimitExceeded(error)
+  }
+}
+
+impl From<IntegrityError> for FetchError {
+  fn from(error: IntegrityError) -> Self {
+    FetchError::Integrity(error)
+  }
+}
+
+impl From<TarballError> for FetchError {
+  fn from(error: TarballError) -> Self {
+    FetchError::Tarball(error)
+  }
+}
+
+impl From<io::Error> for FetchError {
+  fn from(error: io::Error) -> Self {
+    FetchError::Io(error)
+  }
+}
+
+impl From<task::JoinError> for FetchError {
+  fn from(error: task::JoinError) -> Self {
+    FetchError::Io(io::Error::other(error))
+  }
+}
+
+impl From<reqwest::Error> for FetchError {
+  fn from(error: reqwest::Error) -> Self {
+    if error.is_timeout() {
+      // Only the connect timeout is left to reqwest, the others are ours.
+      return FetchError::Timeout(TimeoutError {
+        url: error.url().map(|url| url.to_string()).unwrap_or_default(),
+        phase: TimeoutPhase::Connect,
+        limit: None,
+      });
+    }
+    FetchError::Network(error)
+  }
+}
+
+/// The outcome of an exported async function. A failure is turned into a JS
+/// error on the JS thread, so the promise rejects with a proper `code` and
+/// properties instead of a plain `napi::Error`.
+pub struct JsResult<T>(pub Result<T, FetchError>);
+
+impl<T: TypeName> TypeName for JsResult<T> {
+  fn type_name() -> &'static str {
+    T::type_name()
+  }
+
+  fn value_type() -> ValueType {
+    T::value_type()
+  }
+}
+
+impl<T: ToNapiValue> ToNapiValue for JsResult<T> {
+  unsafe fn to_napi_value(env: sys::napi_env, val: Self) -> napi::Result<sys::napi_value> {
+    match val.0 {
+      Ok(value) => T::to_napi_value(env, value),
+      Err(error) => {
+        let object = error.to_js_error(&Env::from_raw(env))?;
+        Err(napi::Error::from(object.into_unknown()))
+      }
+    }
+  }
+}
+
+#[test]
+fn http_errors_are_classified_by_status() {
+  let error = HttpError::new("https://registry.example/foo.tgz", StatusCode::NOT_FOUND);
+  assert_eq!(error.kind, HttpErrorKind::NotFound);
+  assert_eq!(error.code(), "ERR_PNPM_FETCH_404");
+  assert_eq!(
+    error.to_string(),
+    "GET https://registry.example/foo.tgz: Not Found - 404"
+  );
+  assert_eq!(
+    HttpErrorKind::from_status(StatusCode::BAD_GATEWAY),
+    HttpErrorKind::ServerError
+  );
+}
+
+#[test]
+fn tarball_errors_keep_the_code_of_their_source() {
+  let error = FetchError::from(TarballError {
+    url: "https://registry.example/foo.tgz".to_string(),
+    integrity: Some("sha512-deadbeef".to_string()),
+    source: Box::new(FetchError::Tar(io::Error::other("unexpected EOF"))),
+  });
+  assert_eq!(error.code(), "ERR_PNPM_TARBALL_EXTRACT");
+  assert_eq!(
+    error.to_string(),
+    "Failed to add tarball from \"https://registry.example/foo.tgz\" to store: \
+     Failed to extract the tarball: unexpected EOF"
+  );
+}
+
+#[test]
+fn archive_errors_are_told_apart_from_decompression_errors() {
+  let decompress = io::Error::new(
+    io::ErrorKind::InvalidData,
+    DecompressError(io::Error::other("corrupt deflate stream")),
+  );
+  assert!(matches!(
+    FetchError::from_archive(decompress),
+    FetchError::Decompress(_)
+  ));
+  assert!(matches!(
+    FetchError::from_archive(io::Error::other("invalid tar header")),
+    FetchError::Tar(_)
+  ));
+}
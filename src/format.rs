@@ -0,0 +1,286 @@
+//! The archive formats packages come in besides gzipped tarballs, told apart
+//! by their first bytes, or by how the server or the file name describe them
+//! when those bytes are inconclusive.
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use std::io::{self, Cursor, Read};
+use xz2::read::XzDecoder;
+use zip::{result::ZipError, ZipArchive};
+
+use crate::{CasWriter, ExtractOptions, ExtractedTarball, FetchError, Limit};
+
+/// How many bytes `ArchiveFormat::detect` needs to see: tar archives are
+/// only recognized by the `ustar` magic of their first header.
+pub const MAGIC_LEN: usize = 262;
+
+/// Mode bits of a symlink, as zip archives made on unix record them.
+const S_IFLNK: u32 = 0o120000;
+const S_IFMT: u32 = 0o170000;
+
+/// Symlinks store their target as their contents, which is no longer than a
+/// path can be.
+const MAX_LINK_TARGET_LEN: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+  Tar,
+  Gzip,
+  Zstd,
+  Xz,
+  Bzip2,
+  Zip,
+}
+
+impl ArchiveFormat {
+  /// Tells the format from the start of the archive, falling back to `hint`
+  /// and then to gzip, which is what registries serve.
+  pub fn detect(head: &[u8], hint: Option<ArchiveFormat>) -> Self {
+    ArchiveFormat::from_magic(head)
+      .or(hint)
+      .unwrap_or(ArchiveFormat::Gzip)
+  }
+
+  pub fn from_magic(head: &[u8]) -> Option<Self> {
+    let format = if head.starts_with(&[0x1f, 0x8b]) {
+      ArchiveFormat::Gzip
+    } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+      ArchiveFormat::Zstd
+    } else if head.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+      ArchiveFormat::Xz
+    } else if head.starts_with(b"BZh") {
+      ArchiveFormat::Bzip2
+    } else if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x05\x06") {
+      ArchiveFormat::Zip
+    } else if head.get(257..262) == Some(b"ustar") {
+      ArchiveFormat::Tar
+    } else {
+      return None;
+    };
+    Some(format)
+  }
+
+  /// The format a `Content-Type` names, if it names one. The
+  /// `application/octet-stream` of most registries doesn't.
+  pub fn from_content_type(content_type: &str) -> Option<Self> {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let format = match mime.to_ascii_lowercase().as_str() {
+      "application/gzip" | "application/x-gzip" | "application/x-tgz" => ArchiveFormat::Gzip,
+      "application/zstd" | "application/x-zstd" => ArchiveFormat::Zstd,
+      "application/x-xz" => ArchiveFormat::Xz,
+      "application/x-bzip2" => ArchiveFormat::Bzip2,
+      "application/zip" | "application/x-zip-compressed" => ArchiveFormat::Zip,
+      "application/x-tar" => ArchiveFormat::Tar,
+      _ => return None,
+    };
+    Some(format)
+  }
+
+  /// The format the extension of a file name or URL path names, like
+  /// `.tar.zst` or `.tgz`.
+  pub fn from_extension(name: &str) -> Option<Self> {
+    let name = name.to_ascii_lowercase();
+    let extension = name.rsplit_once('.')?.1;
+    let format = match extension {
+      "tgz" | "gz" => ArchiveFormat::Gzip,
+      "tzst" | "zst" => ArchiveFormat::Zstd,
+      "txz" | "xz" => ArchiveFormat::Xz,
+      "tbz" | "tbz2" | "bz2" => ArchiveFormat::Bzip2,
+      "zip" => ArchiveFormat::Zip,
+      "tar" => ArchiveFormat::Tar,
+      _ => return None,
+    };
+    Some(format)
+  }
+
+  /// Decompresses a tar archive of this format. Zip archives aren't
+  /// compressed tarballs, see `write_zip_to_cas`.
+  pub fn decoder<'a, R: Read + 'a>(self, data: R) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match self {
+      ArchiveFormat::Tar | ArchiveFormat::Zip => Box::new(data),
+      ArchiveFormat::Gzip => Box::new(MultiGzDecoder::new(data)),
+      ArchiveFormat::Zstd => Box::new(zstd::Decoder::new(data)?),
+      ArchiveFormat::Xz => Box::new(XzDecoder::new_multi_decoder(data)),
+      ArchiveFormat::Bzip2 => Box::new(MultiBzDecoder::new(data)),
+    })
+  }
+}
+
+/// Reads up to `len` bytes, fewer only if the data ends first.
+pub fn read_head(data: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+  let mut head = Vec::with_capacity(len);
+  data.take(len as u64).read_to_end(&mut head)?;
+  Ok(head)
+}
+
+/// Unpacks the files of a zip archive into the content-addressable store,
+/// like `write_to_cas` does for tarballs. The central directory is at the end
+/// of a zip archive, so the whole archive is read into memory first.
+pub fn write_zip_to_cas(
+  store_dir: &std::path::Path,
+  mut data: impl Read,
+  options: &ExtractOptions,
+) -> Result<ExtractedTarball, FetchError> {
+  let mut archive = Vec::new();
+  data
+    .read_to_end(&mut archive)
+    .map_err(FetchError::from_archive)?;
+  let mut archive = ZipArchive::new(Cursor::new(archive)).map_err(zip_error)?;
+
+  let mut writer = CasWriter::new(store_dir, options);
+  let mut unpacked = 0;
+  for index in 0..archive.len() {
+    let file = archive.by_index(index).map_err(zip_error)?;
+    writer.count_entry()?;
+    if file.is_dir() {
+      continue;
+    }
+    let (entry_name, package_path) = writer.entry_path(file.name_raw())?;
+    unpacked += file.size();
+    options.limits.check(Limit::UnpackedBytes, unpacked, None)?;
+
+    let mode = file.unix_mode().unwrap_or(0o644);
+    if mode & S_IFMT == S_IFLNK {
+      let mut target = Vec::new();
+      file
+        .take(MAX_LINK_TARGET_LEN)
+        .read_to_end(&mut target)
+        .map_err(FetchError::from_archive)?;
+      let target = String::from_utf8_lossy(&target).into_owned();
+      writer.skip(package_path, "symlink", Some(target));
+      continue;
+    }
+    let size = file.size();
+    writer.add_file(&entry_name, package_path, file, size, mode & 0o7777)?;
+  }
+  Ok(writer.finish())
+}
+
+fn zip_error(error: ZipError) -> FetchError {
+  match error {
+    ZipError::Io(error) => FetchError::from_archive(error),
+    error => FetchError::Tar(io::Error::new(io::ErrorKind::InvalidData, error)),
+  }
+}
+
+#[test]
+fn formats_are_detected_by_magic_bytes_first() {
+  let mut tar = vec![0; 512];
+  tar[257..262].copy_from_slice(b"ustar");
+  assert_eq!(ArchiveFormat::detect(&tar, None), ArchiveFormat::Tar);
+  assert_eq!(
+    ArchiveFormat::detect(b"\x28\xb5\x2f\xfd....", Some(ArchiveFormat::Zip)),
+    ArchiveFormat::Zstd
+  );
+  assert_eq!(
+    ArchiveFormat::detect(b"garbage", Some(ArchiveFormat::Xz)),
+    ArchiveFormat::Xz
+  );
+  assert_eq!(ArchiveFormat::detect(b"garbage", None), ArchiveFormat::Gzip);
+}
+
+#[test]
+fn formats_are_named_by_content_type_and_extension() {
+  assert_eq!(
+    ArchiveFormat::from_content_type("application/zstd; charset=binary"),
+    Some(ArchiveFormat::Zstd)
+  );
+  assert_eq!(
+    ArchiveFormat::from_content_type("application/octet-stream"),
+    None
+  );
+  assert_eq!(
+    ArchiveFormat::from_extension("/artifacts/foo-1.0.0.tar.zst"),
+    Some(ArchiveFormat::Zstd)
+  );
+  assert_eq!(
+    ArchiveFormat::from_extension("foo.TAR.BZ2"),
+    Some(ArchiveFormat::Bzip2)
+  );
+  assert_eq!(ArchiveFormat::from_extension("/foo/-/foo-1.0.0"), None);
+}
+
+#[cfg(test)]
+fn compress(format: ArchiveFormat, tar: &[u8]) -> Vec<u8> {
+  use std::io::Write;
+  match format {
+    ArchiveFormat::Tar => tar.to_vec(),
+    ArchiveFormat::Gzip => {
+      let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+      encoder.write_all(tar).unwrap();
+      encoder.finish().unwrap()
+    }
+    ArchiveFormat::Zstd => zstd::encode_all(tar, 0).unwrap(),
+    ArchiveFormat::Xz => {
+      let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+      encoder.write_all(tar).unwrap();
+      encoder.finish().unwrap()
+    }
+    ArchiveFormat::Bzip2 => {
+      let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+      encoder.write_all(tar).unwrap();
+      encoder.finish().unwrap()
+    }
+    ArchiveFormat::Zip => unreachable!("zip archives aren't tarballs"),
+  }
+}
+
+#[test]
+fn every_format_is_extracted_into_the_store() {
+  let tar = crate::tar_with_files(&[("package/index.js", b"1;\n")]);
+
+  for format in [
+    ArchiveFormat::Tar,
+    ArchiveFormat::Gzip,
+    ArchiveFormat::Zstd,
+    ArchiveFormat::Xz,
+    ArchiveFormat::Bzip2,
+  ] {
+    let store = tempfile::tempdir().unwrap();
+    let data = compress(format, &tar);
+    let extracted =
+      crate::stream::extract_verified(&data[..], store.path(), None, None, &Default::default())
+        .unwrap();
+    let location = &extracted.files["index.js"].location;
+    assert_eq!(std::fs::read(location).unwrap(), b"1;\n", "{:?}", format);
+  }
+}
+
+#[test]
+fn zip_archives_are_extracted_into_the_store() {
+  use std::io::Write;
+  use zip::write::SimpleFileOptions;
+
+  let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+  writer
+    .add_directory("package/", SimpleFileOptions::default())
+    .unwrap();
+  writer
+    .start_file(
+      "package/bin/cli.js",
+      SimpleFileOptions::default().unix_permissions(0o755),
+    )
+    .unwrap();
+  writer.write_all(b"#!/usr/bin/env node\n").unwrap();
+  writer
+    .add_symlink(
+      "package/link.js",
+      "bin/cli.js",
+      SimpleFileOptions::default(),
+    )
+    .unwrap();
+  let data = writer.finish().unwrap().into_inner();
+
+  let store = tempfile::tempdir().unwrap();
+  let extracted =
+    crate::stream::extract_verified(&data[..], store.path(), None, None, &Default::default())
+      .unwrap();
+  let file = &extracted.files["bin/cli.js"];
+  assert_eq!(file.mode, 0o755);
+  assert!(file.location.ends_with("-exec"));
+  assert_eq!(extracted.skipped[0].path, "link.js");
+  assert_eq!(
+    extracted.skipped[0].link_target.as_deref(),
+    Some("bin/cli.js")
+  );
+}
@@ -0,0 +1,156 @@
+use std::io;
+
+use crate::{format::MAGIC_LEN, FetchError};
+
+const RESERVED_FLAGS: u8 = 0b1110_0000;
+const FHCRC: u8 = 0b0000_0010;
+const FEXTRA: u8 = 0b0000_0100;
+const FNAME: u8 = 0b0000_1000;
+const FCOMMENT: u8 = 0b0001_0000;
+
+/// Size of the CRC32 and ISIZE fields at the end of every gzip member.
+const FOOTER_SIZE: usize = 8;
+
+/// Validates the header of the first gzip member, given the first bytes of
+/// the data as `format::read_head` reads them. Inflate would reject a broken
+/// header too, but without saying what is wrong with it.
+///
+/// The optional fields of the header may go on past `head`, and are only
+/// checked as far as it goes. A `head` shorter than `MAGIC_LEN` is all of the
+/// data, so fields running past it are reported as truncated.
+pub fn check_header(head: &[u8]) -> Result<(), FetchError> {
+  let ended = head.len() < MAGIC_LEN;
+  if head.len() < 10 {
+    return Err(truncated("the gzip data ends before its header"));
+  }
+  if head[..2] != [0x1f, 0x8b] {
+    return Err(invalid("not gzip data"));
+  }
+  if head[2] != 8 {
+    return Err(invalid(format!(
+      "unsupported gzip compression method {}",
+      head[2]
+    )));
+  }
+  let flags = head[3];
+  if flags & RESERVED_FLAGS != 0 {
+    return Err(invalid("reserved gzip header flags are set"));
+  }
+
+  let beyond_head = |field: &str| {
+    if ended {
+      Err(truncated(format!("the gzip header ends in its {}", field)))
+    } else {
+      Ok(())
+    }
+  };
+  let mut size = 10;
+  if flags & FEXTRA != 0 {
+    let Some(extra) = head.get(size..size + 2) else {
+      return beyond_head("extra field");
+    };
+    size += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+  }
+  for (flag, field) in [(FNAME, "file name"), (FCOMMENT, "comment")] {
+    if flags & flag != 0 {
+      let Some(end) = head
+        .get(size..)
+        .and_then(|rest| rest.iter().position(|&byte| byte == 0))
+      else {
+        return beyond_head(field);
+      };
+      size += end + 1;
+    }
+  }
+  if flags & FHCRC != 0 {
+    size += 2;
+  }
+  if ended && head.len() < size + FOOTER_SIZE {
+    return Err(truncated("the gzip data ends before its footer"));
+  }
+  Ok(())
+}
+
+/// Explains an unexpected end of the data reported by inflate, which only
+/// says that it ran out of input.
+pub fn truncated_member(error: io::Error) -> io::Error {
+  match error.kind() {
+    io::ErrorKind::UnexpectedEof => io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      "the gzip data ends in the middle of a member",
+    ),
+    _ => error,
+  }
+}
+
+fn truncated(message: impl Into<String>) -> FetchError {
+  FetchError::decompress(io::Error::new(io::ErrorKind::UnexpectedEof, message.into()))
+}
+
+fn invalid(message: impl Into<String>) -> FetchError {
+  FetchError::decompress(io::Error::new(io::ErrorKind::InvalidData, message.into()))
+}
+
+#[cfg(test)]
+fn gzip(data: &[u8]) -> Vec<u8> {
+  use std::io::Write;
+  let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  encoder.write_all(data).unwrap();
+  encoder.finish().unwrap()
+}
+
+#[cfg(test)]
+fn extract(data: &[u8]) -> Result<crate::ExtractedTarball, FetchError> {
+  let store = tempfile::tempdir().unwrap();
+  crate::stream::extract_verified(data, store.path(), None, None, &Default::default())
+}
+
+#[cfg(test)]
+fn decompress_error(data: &[u8]) -> String {
+  let error = extract(data).unwrap_err();
+  assert!(matches!(error, FetchError::Decompress(_)), "{error}");
+  error.to_string()
+}
+
+#[test]
+fn gzip_headers_are_validated() {
+  assert!(decompress_error(b"\x1f").contains("ends before its header"));
+  assert!(decompress_error(b"neither gzip nor a tarball").contains("not gzip data"));
+
+  let tar = crate::tar_with_files(&[("package/index.js", b"1;\n")]);
+  let mut data = gzip(&tar);
+  data[2] = 7;
+  assert!(decompress_error(&data).contains("compression method 7"));
+
+  let mut data = gzip(&tar);
+  data[3] |= FNAME;
+  data.truncate(12);
+  assert!(decompress_error(&data).contains("ends in its file name"));
+}
+
+#[test]
+fn gzip_members_are_all_read() {
+  let tar = crate::tar_with_files(&[("package/index.js", b"1;\n")]);
+  let mut data = gzip(&tar[..700]);
+  data.extend(gzip(&tar[700..]));
+  assert!(extract(&data).unwrap().files.contains_key("index.js"));
+}
+
+#[test]
+fn gzip_footers_are_checked() {
+  let tar = crate::tar_with_files(&[("package/index.js", b"1;\n")]);
+  let mut data = gzip(&tar);
+  let len = data.len();
+  data[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+  assert!(decompress_error(&data).contains("Failed to decompress the tarball"));
+}
+
+#[test]
+fn truncated_gzip_data_is_reported() {
+  let contents = (0..50_000u32)
+    .flat_map(u32::to_le_bytes)
+    .collect::<Vec<_>>();
+  let data = gzip(&crate::tar_with_files(&[("package/index.js", &contents)]));
+  assert!(decompress_error(&data[..data.len() / 2]).contains("ends in the middle of a member"));
+  assert!(decompress_error(&data[..14]).contains("ends before its footer"));
+}
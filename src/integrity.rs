@@ -0,0 +1,119 @@
+use base64::Engine;
+#[cfg(test)]
+use ssri::IntegrityOpts;
+use ssri::{Algorithm, Integrity};
+
+use crate::FetchError;
+
+/// Parses an integrity as lockfiles and registries write it: an SRI string
+/// with any number of hashes, or the hex sha1 `shasum` of old lockfiles,
+/// either bare or as `sha1-<hex>`.
+///
+/// Like browsers do, options (`sha512-...?foo`), hashes with unknown
+/// algorithms and digests that aren't the base64 of a hash of their algorithm
+/// are ignored, as long as one supported hash is left.
+pub fn parse_integrity(value: &str) -> Result<Integrity, FetchError> {
+  let invalid = || FetchError::InvalidInput(format!("Invalid integrity {:?}", value));
+
+  let value = value.trim();
+  if is_sha1_hex(value) {
+    return Integrity::from_hex(value, Algorithm::Sha1).map_err(|_| invalid());
+  }
+
+  let mut hashes = Vec::new();
+  for token in value.split_whitespace() {
+    let token = token.split('?').next().unwrap_or_default();
+    let Some((algorithm, digest)) = token.split_once('-') else {
+      continue;
+    };
+    let Ok(algorithm) = algorithm.parse::<Algorithm>() else {
+      continue;
+    };
+    if algorithm == Algorithm::Sha1 && is_sha1_hex(digest) {
+      hashes.push(Integrity::from_hex(digest, Algorithm::Sha1).map_err(|_| invalid())?);
+    } else if is_digest_of(algorithm, digest) {
+      hashes.push(
+        format!("{}-{}", algorithm, digest)
+          .parse()
+          .map_err(|_| invalid())?,
+      );
+    }
+  }
+  hashes
+    .into_iter()
+    .reduce(|all, hash| all.concat(hash))
+    .ok_or_else(invalid)
+}
+
+fn is_sha1_hex(value: &str) -> bool {
+  value.len() == 40 && value.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn is_digest_of(algorithm: Algorithm, digest: &str) -> bool {
+  let len = match algorithm {
+    Algorithm::Sha512 => 64,
+    Algorithm::Sha384 => 48,
+    Algorithm::Sha256 => 32,
+    Algorithm::Sha1 => 20,
+    Algorithm::Xxh3 => 16,
+    _ => return false,
+  };
+  base64::engine::general_purpose::STANDARD
+    .decode(digest)
+    .is_ok_and(|bytes| bytes.len() == len)
+}
+
+/// Hashes `data` the way `expected` can be checked against: with its
+/// strongest algorithm.
+#[cfg(test)]
+fn hash_like(expected: &Integrity, data: &[u8]) -> Integrity {
+  IntegrityOpts::new()
+    .algorithm(expected.pick_algorithm())
+    .chain(data)
+    .result()
+}
+
+#[test]
+fn integrities_pick_the_strongest_algorithm() {
+  let integrity = parse_integrity(
+    "sha1-2jmj7l5rSw0yVb/vlWAYkK/YBwk= sha512-z4PhNX7vuL3xVChQ1m2AB9Yg5AULVxXcg/SpIdNs6c5H0NE8XYXysP+DGNKHfuwvY7kxvUdBeoGlODJ6+SfaPg==",
+  )
+  .unwrap();
+  assert_eq!(integrity.pick_algorithm(), Algorithm::Sha512);
+  assert!(integrity.matches(&hash_like(&integrity, b"")).is_some());
+}
+
+#[test]
+fn integrities_ignore_options_and_unknown_algorithms() {
+  let integrity = parse_integrity(
+    "md5-1B2M2Y8AsgTpgAmY7PhCfg== sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=?foo",
+  )
+  .unwrap();
+  assert_eq!(integrity.pick_algorithm(), Algorithm::Sha256);
+  assert!(integrity.matches(&hash_like(&integrity, b"")).is_some());
+  assert!(parse_integrity("md5-1B2M2Y8AsgTpgAmY7PhCfg==").is_err());
+
+  let integrity = parse_integrity(
+    "sha512-bogus sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU= sha512-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+  )
+  .unwrap();
+  assert_eq!(integrity.pick_algorithm(), Algorithm::Sha256);
+  assert_eq!(integrity.hashes.len(), 1);
+  for value in ["sha512-bogus", "sha512-", "sha256-AAAA"] {
+    assert!(
+      matches!(parse_integrity(value), Err(FetchError::InvalidInput(_))),
+      "{value}"
+    );
+  }
+}
+
+#[test]
+fn integrities_accept_hex_sha1() {
+  for value in [
+    "da39a3ee5e6b4b0d3255bfef95601890afd80709",
+    "sha1-da39a3ee5e6b4b0d3255bfef95601890afd80709",
+  ] {
+    let integrity = parse_integrity(value).unwrap();
+    assert_eq!(integrity.to_string(), "sha1-2jmj7l5rSw0yVb/vlWAYkK/YBwk=");
+  }
+}
@@ -1,187 +1,642 @@
 #![deny(clippy::all)]
 
-use miette::IntoDiagnostic;
-use reqwest::Client;
+use napi::{bindgen_prelude::Buffer, Env, JsObject};
+use reqwest::{
+  header::{AUTHORIZATION, LOCATION},
+  Client, Url,
+};
 use ssri::{Algorithm, Integrity, IntegrityOpts};
 use std::path::Path;
 use std::{
   collections::HashMap,
-  error::Error,
-  io::{Cursor, Read, Write},
+  future::Future,
+  io::{self, Read, Write},
   path::PathBuf,
-  sync::OnceLock,
+  time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tar::Archive;
-use tokio::task;
-
-const STORE_DIR: &str = "pnpm-store";
+use tar::{Archive, EntryType};
+use timeout::with_timeout;
 
-static CLIENT: OnceLock<Client> = OnceLock::new();
+/// Store used when the caller doesn't pass one, relative to the process cwd.
+const DEFAULT_STORE_DIR: &str = "pnpm-store";
 
 #[macro_use]
 extern crate napi_derive;
 
+mod auth;
+mod entry_path;
+mod error;
+mod fetcher;
+mod format;
+mod gzip;
+mod integrity;
+mod limits;
+mod local;
+mod node_stream;
+mod npmrc;
+mod proxy;
+mod retry;
+mod store;
+mod stream;
+#[cfg(test)]
+mod test_server;
+mod timeout;
+mod tls;
+
+pub use auth::{AuthConfig, RegistryAuth};
+pub use entry_path::{InvalidEntryName, UnsafeEntryPath};
+pub use error::{
+  DecompressError, FetchError, HttpError, HttpErrorKind, IntegrityError, JsResult, TarballError,
+};
+pub use fetcher::{FetcherOptions, NetworkMode, TarballFetcher};
+pub use format::ArchiveFormat;
+pub use integrity::parse_integrity;
+pub use limits::{ExtractLimits, Limit, LimitExceeded};
+pub use local::TarballSource;
+pub use npmrc::Npmrc;
+pub use proxy::ProxyConfig;
+pub use retry::{RetriesExhausted, RetryPolicy};
+pub use timeout::{TimeoutError, TimeoutPhase, Timeouts};
+pub use tls::TlsConfig;
+
+/// Fetches a tarball with default options. Every call shares the same HTTP
+/// client, use `TarballFetcher` to configure it.
 #[napi]
 pub async fn fetch_tarball(
   url: String,
-  integrity: String,
-) -> Result<HashMap<String, String>, napi::Error> {
-  let response = _fetch_tarball(&url).await.unwrap();
-  if let Err(error) = verify_checksum(&response, &integrity) {
-    let error_message = match error {
-      VerifyChecksumError::Mismatch(_) => "Tarball verification failed".to_string(),
-      VerifyChecksumError::Other(error) => error.to_string(),
+  integrity: Option<String>,
+  store_dir: Option<String>,
+) -> JsResult<ExtractedTarball> {
+  match TarballFetcher::with_default_http(store_dir) {
+    Ok(fetcher) => JsResult(fetcher.fetch(url, integrity).await),
+    Err(error) => JsResult(Err(error)),
+  }
+}
+
+/// Extracts a tarball that is already in memory with default options.
+#[napi]
+pub async fn extract_tarball_buffer(
+  data: Buffer,
+  integrity: Option<String>,
+  store_dir: Option<String>,
+) -> JsResult<ExtractedTarball> {
+  let options = FetcherOptions {
+    store_dir,
+    ..Default::default()
+  };
+  let data = io::Cursor::new(Vec::from(data));
+  let extraction = fetcher::extract_from(
+    data,
+    options.store_dir(),
+    ExtractOptions::default(),
+    integrity,
+  );
+  JsResult(extraction.await)
+}
+
+/// Extracts a tarball as a Node readable stream emits it, with default
+/// options.
+#[napi(
+  ts_args_type = "stream: NodeJS.ReadableStream, integrity?: string | undefined | null, storeDir?: string | undefined | null",
+  ts_return_type = "Promise<ExtractedTarball>"
+)]
+pub fn extract_tarball_stream(
+  env: Env,
+  stream: JsObject,
+  integrity: Option<String>,
+  store_dir: Option<String>,
+) -> napi::Result<JsObject> {
+  let options = FetcherOptions {
+    store_dir,
+    ..Default::default()
+  };
+  let store_dir = options.store_dir();
+  fetcher::extract_stream(env, stream, store_dir, ExtractOptions::default(), integrity)
+}
+
+/// How tarballs are downloaded.
+#[derive(Clone)]
+struct HttpSettings {
+  client: Client,
+  retry_policy: RetryPolicy,
+  auth: AuthConfig,
+  timeouts: Timeouts,
+}
+
+/// Redirects followed before giving up, the same limit node-fetch uses.
+const MAX_REDIRECTS: usize = 20;
+
+/// Sends a GET, following redirects by hand so that credentials are only sent
+/// to the origin they were configured for and dropped once a redirect leaves it.
+async fn send_following_redirects(
+  http: &HttpSettings,
+  requested: &str,
+) -> Result<reqwest::Response, FetchError> {
+  let mut url = Url::parse(requested)
+    .map_err(|error| FetchError::InvalidInput(format!("Invalid URL {}: {}", requested, error)))?;
+  let origin = url.origin();
+  let authorization = http.auth.header_for(&url);
+  for _ in 0..=MAX_REDIRECTS {
+    let mut request = http.client.get(url.clone());
+    if let Some(authorization) = authorization.as_ref().filter(|_| url.origin() == origin) {
+      request = request.header(AUTHORIZATION, authorization.clone());
+    }
+    // Waiting for the response headers is waiting for data as well.
+    let res = with_timeout(http.timeouts.read, requested, TimeoutPhase::Read, async {
+      Ok::<_, FetchError>(request.send().await?)
+    })
+    .await?;
+    let location = match res.headers().get(LOCATION) {
+      Some(location) if res.status().is_redirection() => location,
+      _ => return Ok(res),
     };
-    return Err(napi::Error::new(
-      napi::Status::GenericFailure,
-      error_message,
+    url = location
+      .to_str()
+      .ok()
+      .and_then(|location| url.join(location).ok())
+      .ok_or_else(|| FetchError::Redirect(format!("Invalid redirect from {}", url)))?;
+  }
+  Err(FetchError::Redirect(format!(
+    "Too many redirects while fetching {}",
+    url
+  )))
+}
+
+/// Failure of a single download attempt, with how long the server asked us to
+/// wait before trying again.
+type AttemptError = (FetchError, Option<Duration>);
+
+/// Sends the request and checks that it was answered with a 2xx status.
+async fn send_checked(http: &HttpSettings, url: &str) -> Result<reqwest::Response, AttemptError> {
+  let res = send_following_redirects(http, url)
+    .await
+    .map_err(|error| match error {
+      FetchError::Timeout(mut error) if error.phase == TimeoutPhase::Connect => {
+        error.limit = http.timeouts.connect;
+        (error.into(), None)
+      }
+      error => (error, None),
+    })?;
+  if !res.status().is_success() {
+    return Err((
+      HttpError::new(url, res.status()).into(),
+      retry::retry_after(res.headers()),
     ));
   }
-  task::spawn(async move {
-    let decompressed_response = decompress_gzip(&response).unwrap();
-    let parsed: Integrity = integrity.parse().unwrap();
-    let index_location_pb = content_path_from_hex(FileType::Index, parsed.to_hex().1.as_str());
-    let cas_file_map = extract_tarball(index_location_pb.as_path(), decompressed_response).unwrap();
-    Ok(cas_file_map)
+  Ok(res)
+}
+
+/// The next chunk of the response body, or `None` at its end.
+async fn next_chunk(
+  res: &mut reqwest::Response,
+  timeouts: &Timeouts,
+  url: &str,
+) -> Result<Option<bytes::Bytes>, FetchError> {
+  with_timeout(timeouts.read, url, TimeoutPhase::Read, async {
+    Ok(res.chunk().await?)
   })
   .await
-  .unwrap()
 }
 
-#[derive(Debug)]
-pub enum VerifyChecksumError {
-  Mismatch(String),
-  Other(Box<dyn Error>),
+/// Runs `attempt` until it succeeds, fails for good or runs out of retries.
+/// Attempts time out on their own, see `stream::fetch_and_extract_once`.
+async fn retrying<T, F, Fut>(retry_policy: &RetryPolicy, mut attempt: F) -> Result<T, FetchError>
+where
+  F: FnMut() -> Fut,
+  Fut: Future<Output = Result<T, AttemptError>>,
+{
+  let mut attempts = 0;
+  loop {
+    attempts += 1;
+    let (error, retry_after) = match attempt().await {
+      Ok(value) => return Ok(value),
+      Err(failure) => failure,
+    };
+    let error = match error {
+      FetchError::Integrity(mut error) => {
+        error.attempt = Some(attempts);
+        error.into()
+      }
+      error => error,
+    };
+    if !error.is_retryable() {
+      return Err(error);
+    }
+    if attempts > retry_policy.retries {
+      return Err(
+        RetriesExhausted {
+          attempts,
+          source: Box::new(error),
+        }
+        .into(),
+      );
+    }
+    tokio::time::sleep(retry_policy.delay(attempts, retry_after)).await;
+  }
 }
 
-pub fn verify_checksum(
-  response: &bytes::Bytes,
-  expected_checksum: &str,
-) -> Result<(), VerifyChecksumError> {
-  // begin
-  // there are only 2 supported algorithms
-  // sha1 and sha512
-  // so we can be sure that if it doesn't start with sha1, it's going to have to be sha512
+/// Downloads a tarball and extracts it into the store as it arrives. The
+/// index is only written if the tarball matches `integrity`.
+async fn _fetch_tarball(
+  http: &HttpSettings,
+  url: &str,
+  store_dir: &Path,
+  integrity: Option<&str>,
+  options: ExtractOptions,
+) -> Result<ExtractedTarball, FetchError> {
+  retrying(&http.retry_policy, || {
+    stream::fetch_and_extract_once(
+      http,
+      url,
+      store_dir.to_path_buf(),
+      integrity.map(str::to_string),
+      options,
+    )
+  })
+  .await
+}
 
-  let algorithm = if expected_checksum.starts_with("sha1") {
-    Algorithm::Sha1
-  } else {
-    Algorithm::Sha512
-  };
+/// A package file written to the store.
+#[napi(object)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackageFile {
+  /// Absolute path of the file in the content-addressable store.
+  pub location: String,
+  /// Subresource integrity of the file contents, always sha512.
+  pub integrity: String,
+  /// Permission bits from the tarball entry.
+  pub mode: u32,
+  pub size: i64,
+  /// When the file was written to the store, in milliseconds since the epoch.
+  pub checked_at: i64,
+}
 
-  let calculated_checksum = calc_hash(response, algorithm).map_err(VerifyChecksumError::Other)?;
+/// A tarball entry that was left out of the package because it isn't a
+/// regular file.
+#[napi(object)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SkippedEntry {
+  /// Path of the entry inside the package.
+  pub path: String,
+  /// `symlink`, `hardlink`, `character-device`, `block-device`, `fifo` or
+  /// `unknown`.
+  pub kind: String,
+  /// What a link points to.
+  pub link_target: Option<String>,
+}
 
-  if calculated_checksum == expected_checksum {
-    Ok(())
-  } else {
-    Err(VerifyChecksumError::Mismatch(calculated_checksum))
-  }
+/// The files of a package, as written to the store.
+#[napi(object)]
+#[derive(Clone, Debug, Default)]
+pub struct ExtractedTarball {
+  /// Package files by their path inside the package.
+  pub files: HashMap<String, PackageFile>,
+  /// Links and special files, which like in pnpm are not extracted.
+  /// Directories are implied by the files and not listed.
+  pub skipped: Vec<SkippedEntry>,
+  /// Problems that didn't stop the extraction, like entry names that had to
+  /// be repaired.
+  pub warnings: Vec<String>,
+  /// Integrity of the tarball, as verified or, when it wasn't known, as
+  /// computed (sha512). Unset for tarballs that weren't hashed.
+  pub integrity: Option<String>,
 }
 
-fn calc_hash(data: &bytes::Bytes, algorithm: Algorithm) -> Result<String, Box<dyn Error>> {
-  let integrity = if algorithm == Algorithm::Sha1 {
-    let hash = IntegrityOpts::new()
-      .algorithm(Algorithm::Sha1)
-      .chain(data)
-      .result();
-    format!("sha1-{}", hash.to_hex().1)
-  } else {
-    IntegrityOpts::new()
-      .algorithm(Algorithm::Sha512)
-      .chain(data)
-      .result()
-      .to_string()
-  };
-  Ok(integrity)
+/// How tarballs are unpacked into the store.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ExtractOptions {
+  /// Fail on entry names that aren't valid UTF-8, instead of replacing the
+  /// invalid bytes.
+  pub strict_entry_names: bool,
+  pub limits: ExtractLimits,
 }
 
-async fn _fetch_tarball(url: &str) -> Result<bytes::Bytes, Box<dyn Error>> {
-  let client = CLIENT.get_or_init(|| Client::builder().use_rustls_tls().build().unwrap());
-  let res = client.get(url).send().await?;
-  Ok(res.bytes().await?)
+pub fn extract_tarball(
+  store_dir: &Path,
+  index_location: &Path,
+  data: impl Read,
+  options: &ExtractOptions,
+) -> Result<ExtractedTarball, FetchError> {
+  let data = options.limits.reader(Limit::UnpackedBytes, data);
+  let extracted = write_to_cas(store_dir, data, options)?;
+  write_index(store_dir, index_location, &extracted.files)?;
+  Ok(extracted)
 }
 
-pub fn decompress_gzip(gz_data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-  // gzip RFC1952: a valid gzip file has an ISIZE field in the
-  // footer, which is a little-endian u32 number representing the
-  // decompressed size. This is ideal for libdeflate, which needs
-  // preallocating the decompressed buffer.
-  let isize = {
-    let isize_start = gz_data.len() - 4;
-    let isize_bytes: [u8; 4] = gz_data[isize_start..].try_into().into_diagnostic()?;
-    u32::from_le_bytes(isize_bytes) as usize
-  };
+/// Files up to this size are hashed in memory before they're written, larger
+/// ones are hashed while they're streamed into a temporary file in the store.
+const IN_MEMORY_FILE_LIMIT: u64 = 1024 * 1024;
+
+/// Unpacks the regular files of an uncompressed tarball into the
+/// content-addressable store, returning them by their path in the package.
+/// `data` is expected to be counted against `Limit::UnpackedBytes` already,
+/// see `ExtractLimits::reader`.
+fn write_to_cas(
+  store_dir: &Path,
+  data: impl Read,
+  options: &ExtractOptions,
+) -> Result<ExtractedTarball, FetchError> {
+  let mut node_archive = Archive::new(data);
+  let mut writer = CasWriter::new(store_dir, options);
+
+  let entries = node_archive.entries().map_err(FetchError::from_archive)?;
+  for entry in entries {
+    let entry = entry.map_err(FetchError::from_archive)?;
+    writer.count_entry()?;
+
+    let skipped_kind = match entry.header().entry_type() {
+      EntryType::Regular | EntryType::Continuous => None,
+      // Directories are created as needed when linking, and pax global
+      // headers only carry metadata.
+      EntryType::Directory | EntryType::XGlobalHeader => continue,
+      EntryType::Symlink => Some("symlink"),
+      EntryType::Link => Some("hardlink"),
+      EntryType::Char => Some("character-device"),
+      EntryType::Block => Some("block-device"),
+      EntryType::Fifo => Some("fifo"),
+      _ => Some("unknown"),
+    };
+
+    let (entry_name, package_path) = writer.entry_path(&entry.path_bytes())?;
+    if let Some(kind) = skipped_kind {
+      let link_target = entry
+        .link_name()
+        .map_err(FetchError::from_archive)?
+        .map(|target| target.to_string_lossy().into_owned());
+      writer.skip(package_path, kind, link_target);
+      continue;
+    }
 
-  let mut decompressor = libdeflater::Decompressor::new();
+    let size = entry.size();
+    let mode = entry.header().mode().map_err(FetchError::from_archive)?;
+    writer.add_file(&entry_name, package_path, entry, size, mode)?;
+  }
 
-  let mut outbuf = vec![0; isize];
-  decompressor
-    .gzip_decompress(gz_data, &mut outbuf)
-    .into_diagnostic()?;
+  Ok(writer.finish())
+}
 
-  Ok(outbuf)
+/// Collects the entries of an archive, whatever its format, as package files
+/// written to the store and entries that were left out.
+struct CasWriter<'a> {
+  store_dir: &'a Path,
+  options: &'a ExtractOptions,
+  entries: u64,
+  extracted: ExtractedTarball,
 }
 
-pub fn extract_tarball(
-  index_location: &Path,
-  data: Vec<u8>,
-) -> Result<HashMap<String, String>, Box<dyn Error>> {
-  // Generate the tarball archive given the decompressed bytes
-  let mut node_archive = Archive::new(Cursor::new(data));
-
-  // extract to both the global store + node_modules (in the case of them using the pnpm linking algorithm)
-  let mut cas_file_map: HashMap<String, String> = HashMap::new();
-
-  for entry in node_archive.entries().into_diagnostic()? {
-    let mut entry = entry.into_diagnostic()?;
-
-    // Read the contents of the entry
-    let mut buffer = Vec::with_capacity(entry.size() as usize);
-    entry.read_to_end(&mut buffer).into_diagnostic()?;
-
-    let entry_path = entry.path().unwrap();
-    let components = entry_path.components();
-    let cleaned_entry_path: PathBuf = components.skip(1).collect();
-
-    let (_, hex_integrity) = IntegrityOpts::new()
-      .algorithm(Algorithm::Sha512)
-      .chain(&buffer)
-      .result()
-      .to_hex();
-    let file_path =
-      PathBuf::from(STORE_DIR).join(content_path_from_hex(FileType::NonExec, &hex_integrity));
-    if !Path::exists(&file_path) {
-      let parent_dir = file_path.parent().unwrap();
-      std::fs::create_dir_all(parent_dir).unwrap();
-      let mut file = std::fs::File::create(&file_path).unwrap();
-      file.write_all(&buffer).into_diagnostic()?;
+impl<'a> CasWriter<'a> {
+  fn new(store_dir: &'a Path, options: &'a ExtractOptions) -> Self {
+    CasWriter {
+      store_dir,
+      options,
+      entries: 0,
+      extracted: ExtractedTarball::default(),
     }
+  }
+
+  /// Counts an entry of the archive, extracted or not.
+  fn count_entry(&mut self) -> Result<(), FetchError> {
+    self.entries += 1;
+    let limits = &self.options.limits;
+    Ok(limits.check(Limit::Entries, self.entries, None)?)
+  }
+
+  /// Decodes the name of an entry, returning it along with the path of the
+  /// file inside the package.
+  fn entry_path(&mut self, raw_name: &[u8]) -> Result<(String, String), FetchError> {
+    let limits = &self.options.limits;
+    let (entry_name, warning) =
+      entry_path::decode_entry_name(raw_name, self.options.strict_entry_names)?;
+    self.extracted.warnings.extend(warning);
+    let package_path = entry_path::package_path(&entry_name)?;
+    let depth = package_path.split('/').count() as u64;
+    limits.check(Limit::PathDepth, depth, Some(&entry_name))?;
+    Ok((entry_name, package_path))
+  }
+
+  fn skip(&mut self, path: String, kind: &str, link_target: Option<String>) {
+    self.extracted.skipped.push(SkippedEntry {
+      path,
+      kind: kind.to_string(),
+      link_target,
+    });
+  }
 
-    // // Write the contents of the entry into the content-addressable store located at `app.volt_dir`
-    // // We get a hash of the file
-    // let sri = cacache::write_hash_sync(STORE_DIR, &buffer).into_diagnostic()?;
-    // cacache::get_sync(STORE_DIR, &sri).into_diagnostic()?;
+  /// Writes a regular file into the store. `contents` is read from the
+  /// archive, so its errors are the archive's.
+  fn add_file(
+    &mut self,
+    entry_name: &str,
+    path: String,
+    contents: impl Read,
+    size: u64,
+    mode: u32,
+  ) -> Result<(), FetchError> {
+    let limits = &self.options.limits;
+    limits.check(Limit::FileSize, size, Some(entry_name))?;
+    let (file_path, integrity) =
+      write_cas_file(self.store_dir, contents, size, FileType::from_mode(mode))?;
 
     // Insert the name of the file and map it to the hash of the file
-    cas_file_map.insert(
-      cleaned_entry_path.to_str().unwrap().to_string(),
-      file_path.to_string_lossy().into_owned(),
+    self.extracted.files.insert(
+      path,
+      PackageFile {
+        location: file_path.to_string_lossy().into_owned(),
+        integrity: integrity.to_string(),
+        mode,
+        size: size as i64,
+        checked_at: now_millis(),
+      },
     );
+    Ok(())
+  }
+
+  fn finish(self) -> ExtractedTarball {
+    self.extracted
+  }
+}
+
+/// The hash files are stored under.
+fn sha512_of(data: &[u8]) -> Integrity {
+  IntegrityOpts::new()
+    .algorithm(Algorithm::Sha512)
+    .chain(data)
+    .result()
+}
+
+/// Writes one file into the store under the hash of its contents.
+/// Executables get a `-exec` suffix so that linking them keeps them runnable.
+/// `contents` is read from the archive, so its errors are the archive's.
+fn write_cas_file(
+  store_dir: &Path,
+  mut contents: impl Read,
+  size: u64,
+  file_type: FileType,
+) -> Result<(PathBuf, Integrity), FetchError> {
+  let permissions = cas_permissions(file_type);
+  let cas_path =
+    |integrity: &Integrity| store_dir.join(content_path_from_hex(file_type, &integrity.to_hex().1));
+
+  if size <= IN_MEMORY_FILE_LIMIT {
+    let mut buffer = Vec::with_capacity(size as usize);
+    contents
+      .read_to_end(&mut buffer)
+      .map_err(FetchError::from_archive)?;
+    let integrity = sha512_of(&buffer);
+    let file_path = cas_path(&integrity);
+    if !file_path.exists() {
+      let mut temp_file = cas_temp_file(store_dir)?;
+      temp_file.write_all(&buffer)?;
+      persist_cas_file(temp_file, &file_path, permissions)?;
+    }
+    return Ok((file_path, integrity));
   }
-  let dir = PathBuf::from(STORE_DIR).join(index_location);
-  let parent_dir = dir.parent().unwrap();
-  std::fs::create_dir_all(parent_dir).unwrap();
-  std::fs::write(dir, serde_json::to_string(&cas_file_map)?)?;
 
-  Ok(cas_file_map)
+  let mut temp_file = cas_temp_file(store_dir)?;
+  let mut hasher = IntegrityOpts::new().algorithm(Algorithm::Sha512);
+  let mut buffer = vec![0; 64 * 1024];
+  loop {
+    let read = contents
+      .read(&mut buffer)
+      .map_err(FetchError::from_archive)?;
+    if read == 0 {
+      break;
+    }
+    hasher.input(&buffer[..read]);
+    temp_file.write_all(&buffer[..read])?;
+  }
+  let integrity = hasher.result();
+  let file_path = cas_path(&integrity);
+  if !file_path.exists() {
+    persist_cas_file(temp_file, &file_path, permissions)?;
+  }
+  Ok((file_path, integrity))
+}
+
+/// A file to write into before it's moved to its place in the store, see
+/// `persist_cas_file`.
+fn cas_temp_file(store_dir: &Path) -> io::Result<tempfile::NamedTempFile> {
+  std::fs::create_dir_all(store_dir)?;
+  tempfile::NamedTempFile::new_in(store_dir)
+}
+
+/// Moves a file that was written in full into the store, so that fetchers
+/// sharing the store, or one that crashed midway, never leave a partly
+/// written file under a hash.
+fn persist_cas_file(
+  temp_file: tempfile::NamedTempFile,
+  file_path: &Path,
+  permissions: u32,
+) -> io::Result<()> {
+  create_parent_dir(file_path)?;
+  set_permissions(temp_file.path(), permissions)?;
+  temp_file.persist(file_path).map_err(|error| error.error)?;
+  Ok(())
+}
+
+fn now_millis() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map_or(0, |elapsed| elapsed.as_millis() as i64)
+}
+
+fn create_parent_dir(path: &Path) -> io::Result<()> {
+  match path.parent() {
+    Some(parent) => std::fs::create_dir_all(parent),
+    None => Ok(()),
+  }
 }
 
+fn cas_permissions(file_type: FileType) -> u32 {
+  match file_type {
+    FileType::Exec => 0o755,
+    _ => 0o644,
+  }
+}
+
+#[cfg(unix)]
+fn set_permissions(path: &Path, mode: u32) -> io::Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_permissions(_path: &Path, _mode: u32) -> io::Result<()> {
+  Ok(())
+}
+
+/// Writes the package index in the format of pnpm's store, so that pnpm can
+/// reuse the package without downloading it again. Like the files it lists,
+/// it only shows up in the store once it's written in full.
+fn write_index(
+  store_dir: &Path,
+  index_location: &Path,
+  cas_file_map: &HashMap<String, PackageFile>,
+) -> io::Result<()> {
+  let mut temp_file = cas_temp_file(store_dir)?;
+  serde_json::to_writer(&mut temp_file, &package_index(cas_file_map))?;
+  persist_cas_file(
+    temp_file,
+    &store_dir.join(index_location),
+    cas_permissions(FileType::Index),
+  )
+}
+
+fn package_index(cas_file_map: &HashMap<String, PackageFile>) -> serde_json::Value {
+  let files: serde_json::Map<String, serde_json::Value> = cas_file_map
+    .iter()
+    .map(|(path, file)| {
+      let info = serde_json::json!({
+        "checkedAt": file.checked_at,
+        "integrity": file.integrity,
+        "mode": file.mode,
+        "size": file.size,
+      });
+      (path.clone(), info)
+    })
+    .collect();
+
+  let mut index = serde_json::Map::new();
+  // pnpm only uses these for diagnostics, a broken manifest is no reason to
+  // leave them out of the store.
+  let manifest = cas_file_map
+    .get("package.json")
+    .and_then(|file| std::fs::read(&file.location).ok())
+    .and_then(|contents| serde_json::from_slice::<serde_json::Value>(&contents).ok());
+  for field in ["name", "version"] {
+    if let Some(value) = manifest.as_ref().and_then(|manifest| manifest.get(field)) {
+      if value.is_string() {
+        index.insert(field.to_string(), value.clone());
+      }
+    }
+  }
+  index.insert("files".to_string(), files.into());
+  index.into()
+}
+
+#[derive(Clone, Copy)]
 enum FileType {
   Exec,
   NonExec,
   Index,
 }
 
+impl FileType {
+  /// Package files with any executable bit set are stored as executables.
+  fn from_mode(mode: u32) -> Self {
+    if mode & 0o111 != 0 {
+      FileType::Exec
+    } else {
+      FileType::NonExec
+    }
+  }
+}
+
+/// Where the index of the package with this integrity lives in the store.
+fn index_location(integrity: &Integrity) -> PathBuf {
+  content_path_from_hex(FileType::Index, &integrity.to_hex().1)
+}
+
 fn content_path_from_hex(file_type: FileType, hex: &str) -> PathBuf {
   let mut p = PathBuf::new();
   p.push(&hex[0..2]);
@@ -192,7 +647,7 @@ fn content_path_from_hex(file_type: FileType, hex: &str) -> PathBuf {
     FileType::Index => "-index.json",
   };
 
-  p.push(&format!("{}{}", &hex[2..], extension));
+  p.push(format!("{}{}", &hex[2..], extension));
 
   p
 }
@@ -215,3 +670,738 @@ fn create_content_path_from_hex() {
     PathBuf::from("12/34567890abcdef1234567890abcdef12345678-index.json")
   );
 }
+
+#[cfg(test)]
+fn tar_with_files(files: &[(&str, &[u8])]) -> Vec<u8> {
+  let entries = files
+    .iter()
+    .map(|&(path, contents)| (path, 0o644, contents))
+    .collect::<Vec<_>>();
+  tar_with_entries(&entries)
+}
+
+#[cfg(test)]
+fn tar_with_entries(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+  tar_builder(entries).into_inner().unwrap()
+}
+
+/// A tarball builder with `entries` already in it, for adding more than
+/// files. Paths ending with a slash are directories.
+#[cfg(test)]
+fn tar_builder(entries: &[(&str, u32, &[u8])]) -> tar::Builder<Vec<u8>> {
+  let mut builder = tar::Builder::new(Vec::new());
+  for &(path, mode, contents) in entries {
+    let mut header = tar::Header::new_gnu();
+    if path.ends_with('/') {
+      header.set_entry_type(tar::EntryType::Directory);
+    }
+    header.set_size(contents.len() as u64);
+    header.set_mode(mode);
+    builder.append_data(&mut header, path, contents).unwrap();
+  }
+  builder
+}
+
+/// Extracts `data` like `extract_tarball` does, into a store that lives as
+/// long as the returned directory.
+#[cfg(test)]
+fn extract_into_temp_store(
+  data: &[u8],
+  options: &ExtractOptions,
+) -> (tempfile::TempDir, Result<ExtractedTarball, FetchError>) {
+  let store = tempfile::tempdir().unwrap();
+  let extracted = extract_tarball(store.path(), &temp_store_index(), data, options);
+  (store, extracted)
+}
+
+/// Where `extract_into_temp_store` writes the index, relative to the store.
+#[cfg(test)]
+fn temp_store_index() -> PathBuf {
+  content_path_from_hex(FileType::Index, "abcdef")
+}
+
+#[test]
+fn extract_tarball_writes_into_given_store_dir() {
+  let data = tar_with_files(&[("package/index.js", b"module.exports = 1\n")]);
+  let (store, extracted) = extract_into_temp_store(&data, &ExtractOptions::default());
+  let extracted = extracted.unwrap();
+
+  let file_path = PathBuf::from(&extracted.files["index.js"].location);
+  assert!(file_path.starts_with(store.path()));
+  assert_eq!(std::fs::read(file_path).unwrap(), b"module.exports = 1\n");
+  assert!(store.path().join(temp_store_index()).exists());
+}
+
+#[test]
+fn extract_tarball_streams_large_files_into_the_store() {
+  let contents = vec![7; IN_MEMORY_FILE_LIMIT as usize + 1];
+  let data = tar_with_files(&[
+    ("package/big.bin", &contents),
+    ("package/index.js", b"module.exports = 1\n"),
+  ]);
+  let (store, extracted) = extract_into_temp_store(&data, &ExtractOptions::default());
+  let extracted = extracted.unwrap();
+
+  assert_eq!(
+    std::fs::read(&extracted.files["big.bin"].location).unwrap(),
+    contents
+  );
+  assert_eq!(
+    std::fs::read(&extracted.files["index.js"].location).unwrap(),
+    b"module.exports = 1\n"
+  );
+  // Only the CAS directories are left, no temporary files.
+  for entry in std::fs::read_dir(store.path()).unwrap() {
+    assert!(entry.unwrap().file_type().unwrap().is_dir());
+  }
+}
+
+#[test]
+fn extract_tarball_writes_a_pnpm_package_index() {
+  let data = tar_with_files(&[
+    (
+      "package/package.json",
+      br#"{"name":"foo","version":"1.2.3"}"#,
+    ),
+    ("package/index.js", b"module.exports = 1\n"),
+  ]);
+  let (store, extracted) = extract_into_temp_store(&data, &ExtractOptions::default());
+  let extracted = extracted.unwrap();
+
+  let index: serde_json::Value =
+    serde_json::from_slice(&std::fs::read(store.path().join(temp_store_index())).unwrap()).unwrap();
+  assert_eq!(index["name"], "foo");
+  assert_eq!(index["version"], "1.2.3");
+  let file = &index["files"]["index.js"];
+  assert_eq!(
+    file["integrity"],
+    extracted.files["index.js"].integrity.as_str()
+  );
+  assert!(file["integrity"].as_str().unwrap().starts_with("sha512-"));
+  assert_eq!(file["mode"], 0o644);
+  assert_eq!(file["size"], 19);
+  assert!(file["checkedAt"].as_i64().unwrap() > 0);
+  assert!(file.get("location").is_none());
+}
+
+#[test]
+fn extract_tarball_keeps_executables_runnable() {
+  let data = tar_with_entries(&[
+    ("package/bin/cli.js", 0o755, b"{}"),
+    ("package/index.js", 0o644, b"{}"),
+  ]);
+  let (_store, extracted) = extract_into_temp_store(&data, &ExtractOptions::default());
+  let extracted = extracted.unwrap();
+
+  let bin = &extracted.files["bin/cli.js"];
+  assert_eq!(bin.mode, 0o755);
+  assert!(bin.location.ends_with("-exec"));
+  let index = &extracted.files["index.js"];
+  assert_eq!(index.mode, 0o644);
+  assert!(!index.location.ends_with("-exec"));
+  // Same contents, but stored once per file type.
+  assert_ne!(bin.location, index.location);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = |path: &str| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&bin.location), 0o755);
+    assert_eq!(mode(&index.location), 0o644);
+  }
+}
+
+#[test]
+fn extract_tarball_only_stores_regular_files() {
+  let mut builder = tar_builder(&[("package/", 0o755, b""), ("package/index.js", 0o644, b"{}")]);
+  for (entry_type, path) in [
+    (tar::EntryType::Symlink, "package/link.js"),
+    (tar::EntryType::Link, "package/hardlink.js"),
+  ] {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_size(0);
+    header.set_mode(0o644);
+    builder.append_link(&mut header, path, "index.js").unwrap();
+  }
+  let data = builder.into_inner().unwrap();
+  let (_store, extracted) = extract_into_temp_store(&data, &ExtractOptions::default());
+  let extracted = extracted.unwrap();
+
+  assert_eq!(extracted.files.keys().collect::<Vec<_>>(), ["index.js"]);
+  assert_eq!(
+    extracted.skipped,
+    [
+      SkippedEntry {
+        path: "link.js".to_string(),
+        kind: "symlink".to_string(),
+        link_target: Some("index.js".to_string()),
+      },
+      SkippedEntry {
+        path: "hardlink.js".to_string(),
+        kind: "hardlink".to_string(),
+        link_target: Some("index.js".to_string()),
+      },
+    ]
+  );
+}
+
+/// A tarball with a single file whose name is written as is, since `Builder`
+/// refuses to write odd names.
+#[cfg(test)]
+fn tar_with_raw_name(name: &[u8]) -> Vec<u8> {
+  let mut header = tar::Header::new_gnu();
+  header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name);
+  header.set_size(2);
+  header.set_mode(0o644);
+  header.set_cksum();
+  let mut builder = tar::Builder::new(Vec::new());
+  builder.append(&header, &b"{}"[..]).unwrap();
+  builder.into_inner().unwrap()
+}
+
+#[test]
+fn extract_tarball_rejects_entries_outside_of_the_package() {
+  let data = tar_with_raw_name(b"package/../../etc/passwd");
+
+  let (store, extracted) = extract_into_temp_store(&data, &ExtractOptions::default());
+  let error = extracted.unwrap_err();
+  let FetchError::UnsafeEntryPath(error) = error else {
+    panic!("unexpected error: {error}");
+  };
+  assert_eq!(error.entry, "package/../../etc/passwd");
+  assert!(!store.path().join(temp_store_index()).exists());
+}
+
+#[test]
+fn extract_tarball_repairs_names_that_are_not_utf8() {
+  let data = tar_with_raw_name(b"package/caf\xe9.js");
+
+  let (_store, extracted) = extract_into_temp_store(&data, &ExtractOptions::default());
+  let extracted = extracted.unwrap();
+  assert!(extracted.files.contains_key("caf\u{fffd}.js"));
+  assert_eq!(extracted.warnings.len(), 1);
+
+  let strict = ExtractOptions {
+    strict_entry_names: true,
+    ..Default::default()
+  };
+  let error = extract_into_temp_store(&data, &strict).1.unwrap_err();
+  assert!(matches!(error, FetchError::InvalidEntryName(_)));
+}
+
+#[cfg(test)]
+fn http_settings(client: Client, retries: u32) -> HttpSettings {
+  HttpSettings {
+    client,
+    retry_policy: RetryPolicy {
+      retries,
+      min_timeout: Duration::from_millis(1),
+      max_timeout: Duration::from_millis(10),
+      ..Default::default()
+    },
+    auth: AuthConfig::default(),
+    timeouts: Timeouts::default(),
+  }
+}
+
+/// A gzipped tarball with a single `package/index.js`, and its integrity.
+#[cfg(test)]
+fn test_tarball() -> (Vec<u8>, String) {
+  let tar = tar_with_files(&[("package/index.js", b"module.exports = 1\n")]);
+  let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  encoder.write_all(&tar).unwrap();
+  let tarball = encoder.finish().unwrap();
+  let integrity = sha512_of(&tarball).to_string();
+  (tarball, integrity)
+}
+
+#[cfg(test)]
+async fn fetch_into_temp_store(
+  http: &HttpSettings,
+  url: &str,
+  integrity: &str,
+) -> Result<ExtractedTarball, FetchError> {
+  let store = tempfile::tempdir().unwrap();
+  _fetch_tarball(
+    http,
+    url,
+    store.path(),
+    Some(integrity),
+    ExtractOptions::default(),
+  )
+  .await
+}
+
+#[tokio::test]
+async fn fetch_streams_the_tarball_into_the_store() {
+  let (tarball, integrity) = test_tarball();
+  let server = test_server::serve(vec![test_server::response("200 OK", &[], &tarball)]).await;
+  let store = tempfile::tempdir().unwrap();
+
+  let extracted = _fetch_tarball(
+    &http_settings(Client::new(), 0),
+    &server.url,
+    store.path(),
+    Some(&integrity),
+    ExtractOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(
+    std::fs::read(&extracted.files["index.js"].location).unwrap(),
+    b"module.exports = 1\n"
+  );
+  let index_location = index_location(&integrity.parse().unwrap());
+  assert!(store.path().join(index_location).exists());
+}
+
+#[tokio::test]
+async fn fetch_without_integrity_returns_the_computed_one() {
+  let (tarball, integrity) = test_tarball();
+  let server = test_server::serve(vec![test_server::response("200 OK", &[], &tarball)]).await;
+  let store = tempfile::tempdir().unwrap();
+
+  let extracted = _fetch_tarball(
+    &http_settings(Client::new(), 0),
+    &server.url,
+    store.path(),
+    None,
+    ExtractOptions::default(),
+  )
+  .await
+  .unwrap();
+  assert_eq!(extracted.integrity.as_deref(), Some(integrity.as_str()));
+  let index_location = index_location(&integrity.parse().unwrap());
+  assert!(store.path().join(index_location).exists());
+}
+
+#[tokio::test]
+async fn fetch_serves_packages_from_the_store_when_preferring_offline() {
+  let (tarball, integrity) = test_tarball();
+  let server = test_server::serve(vec![test_server::response("200 OK", &[], &tarball)]).await;
+  let store = tempfile::tempdir().unwrap();
+  let fetcher = |prefer_offline, offline| {
+    TarballFetcher::from_options(&FetcherOptions {
+      store_dir: Some(store.path().to_string_lossy().into_owned()),
+      fetch_retries: Some(0),
+      prefer_offline: Some(prefer_offline),
+      offline: Some(offline),
+      ..Default::default()
+    })
+    .unwrap()
+  };
+  let url = format!("{}/foo.tgz", server.url);
+
+  let fetched = fetcher(true, false)
+    .fetch(url.clone(), Some(integrity.clone()))
+    .await
+    .unwrap();
+  let found = fetcher(true, false)
+    .fetch(url.clone(), Some(integrity.clone()))
+    .await
+    .unwrap();
+  assert_eq!(found.files, fetched.files);
+  let found = fetcher(false, true)
+    .fetch(url.clone(), Some(integrity))
+    .await
+    .unwrap();
+  assert_eq!(found.files, fetched.files);
+  assert_eq!(server.requests().len(), 1);
+
+  let other_integrity = sha512_of(b"other").to_string();
+  let error = fetcher(false, true)
+    .fetch(url.clone(), Some(other_integrity))
+    .await
+    .unwrap_err();
+  assert_eq!(error.code(), "ERR_PNPM_NO_OFFLINE_TARBALL");
+
+  let error = fetcher(true, false)
+    .fetch(url, Some("sha512-bogus".to_string()))
+    .await
+    .unwrap_err();
+  assert_eq!(error.code(), "ERR_PNPM_INVALID_INPUT");
+}
+
+#[tokio::test]
+async fn fetch_reads_local_tarballs_even_offline() {
+  let (tarball, integrity) = test_tarball();
+  let dir = tempfile::tempdir().unwrap();
+  let path = dir.path().join("foo.tgz");
+  std::fs::write(&path, &tarball).unwrap();
+  let fetcher = TarballFetcher::from_options(&FetcherOptions {
+    store_dir: Some(dir.path().join("store").to_string_lossy().into_owned()),
+    offline: Some(true),
+    ..Default::default()
+  })
+  .unwrap();
+
+  let file_url = Url::from_file_path(&path).unwrap().to_string();
+  let path = path.to_string_lossy().into_owned();
+  for location in [path.clone(), format!("file:{}", path), file_url] {
+    let extracted = fetcher
+      .fetch(location, Some(integrity.clone()))
+      .await
+      .unwrap();
+    assert!(extracted.files.contains_key("index.js"));
+  }
+
+  let other_integrity = sha512_of(b"other").to_string();
+  let error = fetcher
+    .fetch(path.clone(), Some(other_integrity))
+    .await
+    .unwrap_err();
+  assert_eq!(error.code(), "ERR_PNPM_TARBALL_INTEGRITY");
+  assert!(error.to_string().contains(&path));
+}
+
+#[tokio::test]
+async fn fetch_does_not_write_the_index_of_a_mismatching_tarball() {
+  let (tarball, integrity) = test_tarball();
+  let server = test_server::serve(vec![
+    test_server::response("200 OK", &[], &tarball),
+    test_server::response("200 OK", &[], &tarball),
+  ])
+  .await;
+  let store = tempfile::tempdir().unwrap();
+
+  let other_integrity = sha512_of(b"other").to_string();
+  let error = _fetch_tarball(
+    &http_settings(Client::new(), 1),
+    &server.url,
+    store.path(),
+    Some(&other_integrity),
+    ExtractOptions::default(),
+  )
+  .await
+  .unwrap_err();
+  assert_eq!(error.code(), "ERR_PNPM_TARBALL_INTEGRITY");
+  let FetchError::RetriesExhausted(RetriesExhausted {
+    attempts: 2,
+    source,
+  }) = error
+  else {
+    panic!("unexpected error: {error}");
+  };
+  let FetchError::Integrity(error) = *source else {
+    panic!("unexpected error: {source}");
+  };
+  assert_eq!(error.url.as_deref(), Some(server.url.as_str()));
+  assert_eq!(error.expected, other_integrity);
+  assert_eq!(error.actual, integrity);
+  assert_eq!(error.size, tarball.len() as u64);
+  assert_eq!(error.attempt, Some(2));
+  // Only the package files were written, not the index.
+  for entry in std::fs::read_dir(store.path()).unwrap() {
+    let entry = entry.unwrap().path();
+    for file in std::fs::read_dir(entry).unwrap() {
+      let file = file.unwrap().path();
+      assert!(!file.to_string_lossy().ends_with("-index.json"));
+    }
+  }
+}
+
+#[test]
+fn extract_verified_reports_corrupt_gzip_as_a_decompression_error() {
+  let (mut tarball, _) = test_tarball();
+  // Keep the gzip header, garble the deflate stream.
+  for byte in &mut tarball[10..] {
+    *byte = !*byte;
+  }
+  let integrity = sha512_of(&tarball).to_string();
+  let store = tempfile::tempdir().unwrap();
+
+  let error = stream::extract_verified(
+    &tarball[..],
+    store.path(),
+    Some(&integrity),
+    None,
+    &ExtractOptions::default(),
+  )
+  .unwrap_err();
+  assert_eq!(error.code(), "ERR_PNPM_TARBALL_DECOMPRESS");
+}
+
+#[test]
+fn extract_tarball_enforces_the_limits_on_entries() {
+  let data = tar_with_files(&[
+    ("package/a/b/c.js", b"c"),
+    ("package/index.js", b"module.exports = 1\n"),
+  ]);
+  let extract = |limits: ExtractLimits| {
+    let options = ExtractOptions {
+      limits,
+      ..Default::default()
+    };
+    match extract_into_temp_store(&data, &options).1 {
+      Err(FetchError::LimitExceeded(error)) => (error.limit, error.entry),
+      result => panic!("expected a limit to be exceeded, got {:?}", result),
+    }
+  };
+
+  let limits = ExtractLimits {
+    max_entries: Some(1),
+    ..Default::default()
+  };
+  assert_eq!(extract(limits), (Limit::Entries, None));
+  let limits = ExtractLimits {
+    max_path_depth: Some(2),
+    ..Default::default()
+  };
+  assert_eq!(
+    extract(limits),
+    (Limit::PathDepth, Some("package/a/b/c.js".to_string()))
+  );
+  let limits = ExtractLimits {
+    max_file_size: Some(10),
+    ..Default::default()
+  };
+  assert_eq!(
+    extract(limits),
+    (Limit::FileSize, Some("package/index.js".to_string()))
+  );
+  let limits = ExtractLimits {
+    max_unpacked_bytes: Some(1024),
+    ..Default::default()
+  };
+  assert_eq!(extract(limits), (Limit::UnpackedBytes, None));
+}
+
+#[test]
+fn extract_verified_enforces_the_compressed_limit() {
+  let (tarball, integrity) = test_tarball();
+  let store = tempfile::tempdir().unwrap();
+  let options = ExtractOptions {
+    limits: ExtractLimits {
+      max_compressed_bytes: Some(tarball.len() as u64 - 1),
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+
+  let error =
+    stream::extract_verified(&tarball[..], store.path(), Some(&integrity), None, &options)
+      .unwrap_err();
+  assert_eq!(error.code(), "ERR_PNPM_TARBALL_LIMIT_EXCEEDED");
+  assert!(!store
+    .path()
+    .join(index_location(&integrity.parse().unwrap()))
+    .exists());
+}
+
+#[test]
+fn extract_verified_counts_what_follows_the_end_of_the_tarball_as_unpacked() {
+  let mut tar = tar_with_files(&[("package/index.js", b"module.exports = 1\n")]);
+  tar.resize(tar.len() + 2 * 1024 * 1024, 0);
+  let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  encoder.write_all(&tar).unwrap();
+  let tarball = encoder.finish().unwrap();
+  let store = tempfile::tempdir().unwrap();
+  let options = ExtractOptions {
+    limits: ExtractLimits {
+      max_unpacked_bytes: Some(1024 * 1024),
+      ..Default::default()
+    },
+    ..Default::default()
+  };
+
+  let error =
+    stream::extract_verified(&tarball[..], store.path(), None, None, &options).unwrap_err();
+  let FetchError::LimitExceeded(error) = error else {
+    panic!("unexpected error: {error}");
+  };
+  assert_eq!(error.limit, Limit::UnpackedBytes);
+}
+
+#[tokio::test]
+async fn fetch_retries_server_errors() {
+  let (tarball, integrity) = test_tarball();
+  let server = test_server::serve(vec![
+    test_server::response("503 Service Unavailable", &[], b""),
+    test_server::response("429 Too Many Requests", &[("retry-after", "0")], b""),
+    test_server::response("200 OK", &[], &tarball),
+  ])
+  .await;
+  fetch_into_temp_store(&http_settings(Client::new(), 2), &server.url, &integrity)
+    .await
+    .unwrap();
+  assert_eq!(server.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn fetch_reports_attempts_when_giving_up() {
+  let (_, integrity) = test_tarball();
+  let server = test_server::serve(vec![
+    test_server::response("500 Internal Server Error", &[], b""),
+    test_server::response("502 Bad Gateway", &[], b""),
+  ])
+  .await;
+  let error = fetch_into_temp_store(&http_settings(Client::new(), 1), &server.url, &integrity)
+    .await
+    .unwrap_err();
+  assert!(
+    error.to_string().ends_with("(after 2 attempts)"),
+    "{}",
+    error
+  );
+  assert_eq!(error.code(), "ERR_PNPM_FETCH_502");
+}
+
+#[tokio::test]
+async fn fetch_fails_on_client_errors_without_retrying() {
+  let (_, integrity) = test_tarball();
+  let server = test_server::serve(vec![test_server::response(
+    "404 Not Found",
+    &[],
+    b"<html>not found</html>",
+  )])
+  .await;
+  let error = fetch_into_temp_store(&http_settings(Client::new(), 2), &server.url, &integrity)
+    .await
+    .unwrap_err();
+  match error {
+    FetchError::Http(error) => {
+      assert_eq!(error.kind, HttpErrorKind::NotFound);
+      assert_eq!(error.status, 404);
+      assert_eq!(error.url, server.url);
+    }
+    error => panic!("unexpected error: {}", error),
+  }
+  assert_eq!(server.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn fetch_drops_credentials_on_cross_origin_redirects() {
+  let (tarball, integrity) = test_tarball();
+  let cdn = test_server::serve(vec![test_server::response("200 OK", &[], &tarball)]).await;
+  let registry = test_server::serve(vec![test_server::response(
+    "302 Found",
+    &[("location", &format!("{}/foo.tgz", cdn.url))],
+    b"",
+  )])
+  .await;
+  let client = Client::builder()
+    .redirect(reqwest::redirect::Policy::none())
+    .build()
+    .unwrap();
+  let mut http = http_settings(client, 0);
+  http
+    .auth
+    .insert(
+      &registry.url,
+      &RegistryAuth {
+        token: Some("secret".to_string()),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+
+  fetch_into_temp_store(&http, &registry.url, &integrity)
+    .await
+    .unwrap();
+  assert!(registry.requests()[0].contains("authorization: Bearer secret"));
+  assert!(!cdn.requests()[0].to_lowercase().contains("authorization"));
+}
+
+#[tokio::test]
+async fn fetch_goes_through_an_authenticated_proxy() {
+  let (tarball, integrity) = test_tarball();
+  let proxy = test_server::serve(vec![test_server::response("200 OK", &[], &tarball)]).await;
+  let proxy_url = proxy.url.replace("http://", "http://user:pass@");
+  let builder = Client::builder().redirect(reqwest::redirect::Policy::none());
+  let client = ProxyConfig::new(Some(proxy_url), None, None)
+    .apply(builder)
+    .unwrap()
+    .build()
+    .unwrap();
+
+  fetch_into_temp_store(
+    &http_settings(client, 0),
+    "http://registry.invalid/foo/-/foo-1.0.0.tgz",
+    &integrity,
+  )
+  .await
+  .unwrap();
+  let request = &proxy.requests()[0];
+  assert!(request.starts_with("GET http://registry.invalid/foo/-/foo-1.0.0.tgz HTTP/1.1"));
+  assert!(request.contains("proxy-authorization: Basic dXNlcjpwYXNz"));
+}
+
+#[tokio::test]
+async fn fetch_times_out_on_a_stalled_server() {
+  let (_, integrity) = test_tarball();
+  let server = test_server::stalled_server().await;
+  let url = format!("{}/foo.tgz", server.url);
+  let mut http = http_settings(Client::new(), 1);
+  http.timeouts.total = Some(Duration::from_millis(50));
+
+  let error = fetch_into_temp_store(&http, &url, &integrity)
+    .await
+    .unwrap_err();
+  assert_eq!(error.code(), "ERR_PNPM_FETCH_TIMEOUT");
+  match error {
+    FetchError::RetriesExhausted(RetriesExhausted { attempts, source }) => {
+      assert_eq!(attempts, 2);
+      assert!(matches!(
+        *source,
+        FetchError::Timeout(TimeoutError {
+          phase: TimeoutPhase::Total,
+          ..
+        })
+      ));
+    }
+    error => panic!("unexpected error: {}", error),
+  }
+}
+
+#[tokio::test]
+async fn fetch_times_out_waiting_for_the_response_headers() {
+  let (_, integrity) = test_tarball();
+  let server = test_server::stalled_server().await;
+  let url = format!("{}/foo.tgz", server.url);
+  let mut http = http_settings(Client::new(), 0);
+  http.timeouts.read = Some(Duration::from_millis(50));
+  http.timeouts.total = None;
+
+  let error = fetch_into_temp_store(&http, &url, &integrity)
+    .await
+    .unwrap_err();
+  let FetchError::RetriesExhausted(RetriesExhausted { source, .. }) = error else {
+    panic!("unexpected error: {error}");
+  };
+  assert!(matches!(
+    *source,
+    FetchError::Timeout(TimeoutError {
+      phase: TimeoutPhase::Read,
+      ..
+    })
+  ));
+}
+
+#[tokio::test]
+async fn fetch_does_not_extract_a_body_cut_short_by_the_timeout() {
+  let tar = tar_with_files(&[("package/a.js", b"a"), ("package/b.js", &[b'b'; 4096])]);
+  let mut response = test_server::response("200 OK", &[], &tar);
+  response.truncate(response.len() - tar.len() + 1024);
+  let server = test_server::serve_stalled(response).await;
+  let store = tempfile::tempdir().unwrap();
+  let mut http = http_settings(Client::new(), 0);
+  http.timeouts.total = Some(Duration::from_millis(300));
+
+  let error = _fetch_tarball(
+    &http,
+    &format!("{}/foo.tar", server.url),
+    store.path(),
+    None,
+    ExtractOptions::default(),
+  )
+  .await
+  .unwrap_err();
+  assert_eq!(error.code(), "ERR_PNPM_FETCH_TIMEOUT");
+  for entry in std::fs::read_dir(store.path()).unwrap() {
+    let entry = entry.unwrap().path();
+    for file in std::fs::read_dir(entry).unwrap() {
+      let file = file.unwrap().path();
+      assert!(!file.to_string_lossy().ends_with("-index.json"));
+    }
+  }
+}
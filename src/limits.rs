@@ -0,0 +1,179 @@
+use std::{
+  error::Error,
+  fmt,
+  io::{self, Read},
+};
+
+const MIB: u64 = 1024 * 1024;
+
+/// Bounds on what a tarball may download and unpack to, so that a malicious
+/// package can't exhaust the memory, disk or inodes of the machine installing
+/// it. `None` means no limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtractLimits {
+  /// Size of the tarball as downloaded.
+  pub max_compressed_bytes: Option<u64>,
+  /// Size of the tarball once decompressed, tar headers included.
+  pub max_unpacked_bytes: Option<u64>,
+  /// Number of entries in the tarball, whether they're extracted or not.
+  pub max_entries: Option<u64>,
+  /// Size of any one file in the package.
+  pub max_file_size: Option<u64>,
+  /// Number of path components of a file inside the package.
+  pub max_path_depth: Option<u64>,
+}
+
+/// Generous enough for the largest packages on the npm registry.
+impl Default for ExtractLimits {
+  fn default() -> Self {
+    ExtractLimits {
+      max_compressed_bytes: Some(1024 * MIB),
+      max_unpacked_bytes: Some(4096 * MIB),
+      max_entries: Some(500_000),
+      max_file_size: Some(1024 * MIB),
+      max_path_depth: Some(128),
+    }
+  }
+}
+
+impl ExtractLimits {
+  /// Fails if `value` is over the limit of its kind.
+  pub fn check(&self, limit: Limit, value: u64, entry: Option<&str>) -> Result<(), LimitExceeded> {
+    match self.max(limit) {
+      Some(max) if value > max => Err(LimitExceeded {
+        limit,
+        max,
+        entry: entry.map(str::to_string),
+      }),
+      _ => Ok(()),
+    }
+  }
+
+  fn max(&self, limit: Limit) -> Option<u64> {
+    match limit {
+      Limit::CompressedBytes => self.max_compressed_bytes,
+      Limit::UnpackedBytes => self.max_unpacked_bytes,
+      Limit::Entries => self.max_entries,
+      Limit::FileSize => self.max_file_size,
+      Limit::PathDepth => self.max_path_depth,
+    }
+  }
+
+  /// Counts the bytes read through `inner` against the limit of their kind.
+  pub fn reader<R>(&self, limit: Limit, inner: R) -> LimitedReader<R> {
+    LimitedReader {
+      inner,
+      limit,
+      limits: *self,
+      read: 0,
+    }
+  }
+}
+
+/// The kinds of limits in `ExtractLimits`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+  CompressedBytes,
+  UnpackedBytes,
+  Entries,
+  FileSize,
+  PathDepth,
+}
+
+impl Limit {
+  /// The name of the option that sets this limit.
+  pub fn option_name(self) -> &'static str {
+    match self {
+      Limit::CompressedBytes => "maxCompressedBytes",
+      Limit::UnpackedBytes => "maxUnpackedBytes",
+      Limit::Entries => "maxEntries",
+      Limit::FileSize => "maxFileSize",
+      Limit::PathDepth => "maxPathDepth",
+    }
+  }
+}
+
+/// A tarball that is larger, or unpacks to more, than the limits allow.
+#[derive(Debug)]
+pub struct LimitExceeded {
+  pub limit: Limit,
+  pub max: u64,
+  /// The entry that went over the limit, for limits on single entries.
+  pub entry: Option<String>,
+}
+
+impl fmt::Display for LimitExceeded {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let entry = self.entry.as_deref().unwrap_or_default();
+    match self.limit {
+      Limit::CompressedBytes => write!(f, "The tarball is larger than {} bytes", self.max),
+      Limit::UnpackedBytes => write!(f, "The tarball unpacks to more than {} bytes", self.max),
+      Limit::Entries => write!(f, "The tarball has more than {} entries", self.max),
+      Limit::FileSize => write!(
+        f,
+        "Tarball entry {:?} is larger than {} bytes",
+        entry, self.max
+      ),
+      Limit::PathDepth => write!(
+        f,
+        "Tarball entry {:?} is nested more than {} levels deep",
+        entry, self.max
+      ),
+    }?;
+    write!(f, " (see {})", self.limit.option_name())
+  }
+}
+
+impl Error for LimitExceeded {}
+
+/// Fails reads once more bytes went through than a limit allows. The error
+/// carries a `LimitExceeded`, see `FetchError::from_archive`.
+pub struct LimitedReader<R> {
+  inner: R,
+  limit: Limit,
+  limits: ExtractLimits,
+  read: u64,
+}
+
+impl<R> LimitedReader<R> {
+  pub fn into_inner(self) -> R {
+    self.inner
+  }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    self.read += read as u64;
+    self
+      .limits
+      .check(self.limit, self.read, None)
+      .map_err(io::Error::other)?;
+    Ok(read)
+  }
+}
+
+#[test]
+fn limited_readers_fail_past_the_limit() {
+  let limits = ExtractLimits {
+    max_unpacked_bytes: Some(4),
+    ..Default::default()
+  };
+  let mut within = Vec::new();
+  limits
+    .reader(Limit::UnpackedBytes, &b"1234"[..])
+    .read_to_end(&mut within)
+    .unwrap();
+  assert_eq!(within, b"1234");
+
+  let error = limits
+    .reader(Limit::UnpackedBytes, &b"12345"[..])
+    .read_to_end(&mut Vec::new())
+    .unwrap_err();
+  let exceeded = error.downcast::<LimitExceeded>().unwrap();
+  assert_eq!(exceeded.limit, Limit::UnpackedBytes);
+  assert_eq!(
+    exceeded.to_string(),
+    "The tarball unpacks to more than 4 bytes (see maxUnpackedBytes)"
+  );
+}
@@ -0,0 +1,97 @@
+//! Tarballs on the local filesystem, like the `file:../pkg.tgz` dependencies
+//! of pnpm, go through the same verification and extraction as downloaded
+//! ones, only without the network.
+
+use reqwest::Url;
+use std::{fs::File, io::BufReader, path::Path, path::PathBuf};
+
+use crate::{stream, ArchiveFormat, ExtractOptions, ExtractedTarball, FetchError, Limit};
+
+/// Where a tarball is fetched from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TarballSource {
+  /// An `http:` or `https:` URL.
+  Remote(String),
+  /// A path given as is, as a `file:` spec or as a `file://` URL. Relative
+  /// paths are relative to the cwd.
+  Local(PathBuf),
+}
+
+impl TarballSource {
+  pub fn parse(location: &str) -> Result<Self, FetchError> {
+    if location.starts_with("file://") {
+      let path = Url::parse(location)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or_else(|| FetchError::InvalidInput(format!("Invalid file URL {:?}", location)))?;
+      return Ok(TarballSource::Local(path));
+    }
+    if let Some(path) = location.strip_prefix("file:") {
+      return Ok(TarballSource::Local(PathBuf::from(path)));
+    }
+    match Url::parse(location) {
+      Ok(url) if matches!(url.scheme(), "http" | "https") => {
+        Ok(TarballSource::Remote(location.to_string()))
+      }
+      // Windows paths like `C:\pkg.tgz` parse as URLs with a one letter
+      // scheme.
+      Ok(url) if url.scheme().len() > 1 => Err(FetchError::InvalidInput(format!(
+        "Unsupported protocol {:?} in {:?}",
+        url.scheme(),
+        location
+      ))),
+      _ => Ok(TarballSource::Local(PathBuf::from(location))),
+    }
+  }
+}
+
+/// Verifies and extracts a tarball from the filesystem into the store.
+pub fn extract_local(
+  path: &Path,
+  store_dir: &Path,
+  expected_checksum: Option<&str>,
+  options: &ExtractOptions,
+) -> Result<ExtractedTarball, FetchError> {
+  let file = File::open(path)?;
+  options
+    .limits
+    .check(Limit::CompressedBytes, file.metadata()?.len(), None)?;
+  let hint = path
+    .file_name()
+    .and_then(|name| name.to_str())
+    .and_then(ArchiveFormat::from_extension);
+  stream::extract_verified(
+    BufReader::new(file),
+    store_dir,
+    expected_checksum,
+    hint,
+    options,
+  )
+}
+
+#[test]
+fn sources_are_told_apart_by_their_protocol() {
+  let local = |path: &str| TarballSource::Local(PathBuf::from(path));
+  assert_eq!(
+    TarballSource::parse("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz").unwrap(),
+    TarballSource::Remote("https://registry.npmjs.org/foo/-/foo-1.0.0.tgz".to_string())
+  );
+  assert_eq!(
+    TarballSource::parse("file:../foo.tgz").unwrap(),
+    local("../foo.tgz")
+  );
+  assert_eq!(
+    TarballSource::parse("./foo.tgz").unwrap(),
+    local("./foo.tgz")
+  );
+  assert_eq!(
+    TarballSource::parse("C:\\foo.tgz").unwrap(),
+    local("C:\\foo.tgz")
+  );
+  #[cfg(unix)]
+  assert_eq!(
+    TarballSource::parse("file:///tmp/foo%20bar.tgz").unwrap(),
+    local("/tmp/foo bar.tgz")
+  );
+  assert!(TarballSource::parse("git+ssh://github.com/foo/bar.git").is_err());
+}
@@ -0,0 +1,273 @@
+//! Reading a Node readable stream from the blocking extraction thread. The
+//! chunks are queued as they're emitted, and the stream is paused while the
+//! extraction is behind.
+
+use bytes::{Buf, Bytes};
+use napi::{
+  bindgen_prelude::Buffer,
+  threadsafe_function::{
+    ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+  },
+  CallContext, Env, JsFunction, JsObject, JsUnknown, ValueType,
+};
+use std::{
+  cell::RefCell,
+  io::{self, Read},
+  rc::Rc,
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+};
+use tokio::sync::mpsc;
+
+/// Chunks queued before the stream is paused.
+const MAX_QUEUED_CHUNKS: usize = 16;
+
+/// The sending end of the queue, as the stream's listeners share it on the
+/// JS thread.
+struct ChunkSender {
+  sender: RefCell<Option<mpsc::UnboundedSender<io::Result<Bytes>>>>,
+  failure: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl ChunkSender {
+  /// Queues a chunk, unless the stream already ended or nothing is read
+  /// anymore.
+  fn send(&self, chunk: Bytes) -> bool {
+    match &*self.sender.borrow() {
+      Some(sender) => sender.send(Ok(chunk)).is_ok(),
+      None => false,
+    }
+  }
+
+  fn end(&self) {
+    self.sender.borrow_mut().take();
+  }
+
+  /// Ends the stream with an error, which the reader fails with and which is
+  /// kept as the reason the extraction failed.
+  fn fail(&self, kind: io::ErrorKind, message: String) {
+    if let Some(sender) = self.sender.borrow_mut().take() {
+      if let Ok(mut failure) = self.failure.lock() {
+        failure.get_or_insert(io::Error::new(kind, message.clone()));
+      }
+      let _ = sender.send(Err(io::Error::new(kind, message)));
+    }
+  }
+}
+
+/// Why the stream failed, if it did.
+pub struct StreamFailure(Arc<Mutex<Option<io::Error>>>);
+
+impl StreamFailure {
+  pub fn take(&self) -> Option<io::Error> {
+    self.0.lock().ok()?.take()
+  }
+}
+
+/// Reads the chunks of a Node readable stream, blocking until they arrive.
+pub struct NodeStreamReader {
+  chunks: mpsc::UnboundedReceiver<io::Result<Bytes>>,
+  current: Bytes,
+  queued: Arc<AtomicUsize>,
+  paused: Arc<AtomicBool>,
+  resume: ThreadsafeFunction<(), ErrorStrategy::Fatal>,
+}
+
+impl NodeStreamReader {
+  /// Listens to the events of `stream`, which starts it flowing, or fails
+  /// right away if the stream is already over.
+  pub fn subscribe(env: &Env, stream: &JsObject) -> napi::Result<(Self, StreamFailure)> {
+    let (sender, chunks) = mpsc::unbounded_channel();
+    let failure = Arc::new(Mutex::new(None));
+    let sender = Rc::new(ChunkSender {
+      sender: RefCell::new(Some(sender)),
+      failure: failure.clone(),
+    });
+    let queued = Arc::new(AtomicUsize::new(0));
+    let paused = Arc::new(AtomicBool::new(false));
+
+    // `stream.resume` bound to the stream, so that it can be called from the
+    // extraction thread.
+    let resume: JsFunction = stream.get_named_property("resume")?;
+    let resume_object = resume.coerce_to_object()?;
+    let bind: JsFunction = resume_object.get_named_property("bind")?;
+    let resume: JsFunction = bind.call(Some(&resume_object), &[stream])?.try_into()?;
+    let mut resume = resume.create_threadsafe_function(0, |_: ThreadSafeCallContext<()>| {
+      Ok(Vec::<JsUnknown>::new())
+    })?;
+    // Only the pending extraction should keep the process alive, not the way
+    // back into the stream.
+    resume.unref(env)?;
+
+    match finished(stream)? {
+      // None of its events would fire anymore.
+      Some((kind, message)) => sender.fail(kind, message),
+      None => listen(env, stream, sender, queued.clone(), paused.clone())?,
+    }
+
+    let reader = NodeStreamReader {
+      chunks,
+      current: Bytes::new(),
+      queued,
+      paused,
+      resume,
+    };
+    Ok((reader, StreamFailure(failure)))
+  }
+
+  /// Lets the stream flow again once the queue is half empty, or for good
+  /// when nothing is read anymore.
+  fn resume_if(&self, caught_up: bool) {
+    if caught_up && self.paused.swap(false, Ordering::SeqCst) {
+      self
+        .resume
+        .call((), ThreadsafeFunctionCallMode::NonBlocking);
+    }
+  }
+}
+
+/// Why nothing can be read from `stream` anymore, if it already ended, failed
+/// or was destroyed.
+fn finished(stream: &JsObject) -> napi::Result<Option<(io::ErrorKind, String)>> {
+  let property = |name: &str| stream.get_named_property::<JsUnknown>(name);
+  let flag = |name: &str| property(name)?.coerce_to_bool()?.get_value();
+  let errored = property("errored")?;
+  let finished = if !matches!(errored.get_type()?, ValueType::Null | ValueType::Undefined) {
+    Some((io::ErrorKind::Other, error_message(errored)))
+  } else if flag("readableEnded")? {
+    let message = "The stream already ended before it was read";
+    Some((io::ErrorKind::UnexpectedEof, message.to_string()))
+  } else if flag("destroyed")? {
+    let message = "The stream closed before it ended";
+    Some((io::ErrorKind::UnexpectedEof, message.to_string()))
+  } else {
+    None
+  };
+  Ok(finished)
+}
+
+fn error_message(error: JsUnknown) -> String {
+  error
+    .coerce_to_string()
+    .and_then(|message| message.into_utf8())
+    .and_then(|message| message.into_owned())
+    .unwrap_or_else(|_| "The stream failed".to_string())
+}
+
+/// Queues the chunks `stream` emits, which starts it flowing.
+fn listen(
+  env: &Env,
+  stream: &JsObject,
+  sender: Rc<ChunkSender>,
+  queued: Arc<AtomicUsize>,
+  paused: Arc<AtomicBool>,
+) -> napi::Result<()> {
+  let on_data = {
+    let (sender, queued, paused) = (sender.clone(), queued.clone(), paused.clone());
+    move |ctx: CallContext<'_>| {
+      let chunk = match ctx.get::<Buffer>(0) {
+        Ok(chunk) => Bytes::from(Vec::from(chunk)),
+        Err(_) => {
+          let message = "The stream emitted a chunk that isn't a Buffer";
+          sender.fail(io::ErrorKind::InvalidData, message.to_string());
+          return ctx.env.get_undefined();
+        }
+      };
+      // Counted before it's sent, so that the reader never sees it uncounted.
+      let count = queued.fetch_add(1, Ordering::SeqCst) + 1;
+      if !sender.send(chunk) {
+        queued.fetch_sub(1, Ordering::SeqCst);
+      } else if count >= MAX_QUEUED_CHUNKS {
+        paused.store(true, Ordering::SeqCst);
+        let this: JsObject = ctx.this()?;
+        let pause: JsFunction = this.get_named_property("pause")?;
+        pause.call_without_args(Some(&this))?;
+        // The extraction may have caught up before it could see the pause.
+        if queued.load(Ordering::SeqCst) <= MAX_QUEUED_CHUNKS / 2
+          && paused.swap(false, Ordering::SeqCst)
+        {
+          let resume: JsFunction = this.get_named_property("resume")?;
+          resume.call_without_args(Some(&this))?;
+        }
+      }
+      ctx.env.get_undefined()
+    }
+  };
+  let on_end = {
+    let sender = sender.clone();
+    move |ctx: CallContext<'_>| {
+      sender.end();
+      ctx.env.get_undefined()
+    }
+  };
+  let on_error = {
+    let sender = sender.clone();
+    move |ctx: CallContext<'_>| {
+      let message = match ctx.get::<JsUnknown>(0) {
+        Ok(error) => error_message(error),
+        Err(_) => "The stream failed".to_string(),
+      };
+      sender.fail(io::ErrorKind::Other, message);
+      ctx.env.get_undefined()
+    }
+  };
+  // A destroyed stream closes without ending.
+  let on_close = move |ctx: CallContext<'_>| {
+    let message = "The stream closed before it ended";
+    sender.fail(io::ErrorKind::UnexpectedEof, message.to_string());
+    ctx.env.get_undefined()
+  };
+
+  let on: JsFunction = stream.get_named_property("on")?;
+  for (event, listener) in [
+    (
+      "error",
+      env.create_function_from_closure("onError", on_error)?,
+    ),
+    ("end", env.create_function_from_closure("onEnd", on_end)?),
+    (
+      "close",
+      env.create_function_from_closure("onClose", on_close)?,
+    ),
+    // Last, as adding it starts the stream flowing.
+    ("data", env.create_function_from_closure("onData", on_data)?),
+  ] {
+    on.call(
+      Some(stream),
+      &[
+        env.create_string(event)?.into_unknown(),
+        listener.into_unknown(),
+      ],
+    )?;
+  }
+  Ok(())
+}
+
+impl Read for NodeStreamReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.current.is_empty() {
+      match self.chunks.blocking_recv() {
+        Some(Ok(chunk)) => {
+          let queued = self.queued.fetch_sub(1, Ordering::SeqCst) - 1;
+          self.resume_if(queued <= MAX_QUEUED_CHUNKS / 2);
+          self.current = chunk;
+        }
+        Some(Err(error)) => return Err(error),
+        None => return Ok(0),
+      }
+    }
+    let read = buf.len().min(self.current.len());
+    buf[..read].copy_from_slice(&self.current[..read]);
+    self.current.advance(read);
+    Ok(read)
+  }
+}
+
+impl Drop for NodeStreamReader {
+  fn drop(&mut self) {
+    // Don't leave the stream paused when the extraction gives up early.
+    self.resume_if(true);
+  }
+}
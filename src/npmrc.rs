@@ -0,0 +1,260 @@
+use base64::Engine;
+use std::{
+  collections::HashMap,
+  io,
+  path::{Path, PathBuf},
+};
+
+use crate::{FetchError, RegistryAuth};
+
+pub const DEFAULT_REGISTRY: &str = "https://registry.npmjs.org/";
+
+/// The settings of one or more `.npmrc` files that matter for fetching.
+#[derive(Clone, Default)]
+pub struct Npmrc {
+  pub registry: Option<String>,
+  /// `//host/path/:_authToken` style entries, keyed by `//host/path/`.
+  pub auth: HashMap<String, RegistryAuth>,
+  /// Unprefixed `_authToken`/`_auth`, which apply to `registry`.
+  pub default_auth: RegistryAuth,
+  pub strict_ssl: Option<bool>,
+  pub proxy: Option<String>,
+  pub https_proxy: Option<String>,
+  pub no_proxy: Option<String>,
+  pub cafile: Option<PathBuf>,
+  /// Inline PEM certificates from `ca=` or repeated `ca[]=` lines.
+  pub ca: Vec<String>,
+  pub cert: Option<String>,
+  pub key: Option<String>,
+}
+
+impl Npmrc {
+  /// Reads the user's `.npmrc` (`$NPM_CONFIG_USERCONFIG` or `~/.npmrc`) and
+  /// then `dir/.npmrc`, with the project file taking precedence.
+  pub fn load(dir: &Path) -> Result<Self, FetchError> {
+    let env = |name: &str| std::env::var(name).ok();
+    let user_config = env("NPM_CONFIG_USERCONFIG")
+      .or_else(|| env("npm_config_userconfig"))
+      .map(PathBuf::from)
+      .or_else(|| {
+        env("HOME")
+          .or_else(|| env("USERPROFILE"))
+          .map(|home| PathBuf::from(home).join(".npmrc"))
+      });
+
+    let mut npmrc = Npmrc::default();
+    for path in user_config
+      .into_iter()
+      .chain(std::iter::once(dir.join(".npmrc")))
+    {
+      match std::fs::read_to_string(&path) {
+        Ok(contents) => npmrc.extend(Npmrc::parse(&contents, &env).map_err(|error| {
+          FetchError::InvalidInput(format!("Failed to read {}: {}", path.display(), error))
+        })?),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => {
+          return Err(FetchError::Io(io::Error::new(
+            error.kind(),
+            format!("Failed to read {}: {}", path.display(), error),
+          )))
+        }
+      }
+    }
+    Ok(npmrc)
+  }
+
+  /// Parses the contents of a `.npmrc` file, replacing `${VAR}` references
+  /// with values from `env`.
+  pub fn parse(contents: &str, env: &dyn Fn(&str) -> Option<String>) -> Result<Self, String> {
+    let mut npmrc = Npmrc::default();
+    for line in contents.lines() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+        continue;
+      }
+      let Some((key, value)) = line.split_once('=') else {
+        continue;
+      };
+      let key = replace_env(key.trim(), env)?;
+      let value = replace_env(unquote(value.trim()), env)?;
+      npmrc.set(&key, value);
+    }
+    Ok(npmrc)
+  }
+
+  fn set(&mut self, key: &str, value: String) {
+    if key.starts_with("//") {
+      if let Some((registry, field)) = key.rsplit_once(':') {
+        let auth = self.auth.entry(registry.to_string()).or_default();
+        set_auth_field(auth, field, value);
+      }
+      return;
+    }
+    match key {
+      "registry" => self.registry = Some(value),
+      "strict-ssl" => self.strict_ssl = Some(value != "false"),
+      "proxy" => self.proxy = Some(value),
+      "https-proxy" => self.https_proxy = Some(value),
+      "no-proxy" | "noproxy" => self.no_proxy = Some(value),
+      "cafile" => self.cafile = Some(PathBuf::from(value)),
+      "ca" => self.ca = vec![unescape_pem(&value)],
+      "ca[]" => self.ca.push(unescape_pem(&value)),
+      "cert" => self.cert = Some(unescape_pem(&value)),
+      "key" => self.key = Some(unescape_pem(&value)),
+      _ => set_auth_field(&mut self.default_auth, key, value),
+    }
+  }
+
+  /// Overrides these settings with the ones of a more specific file.
+  pub fn extend(&mut self, other: Npmrc) {
+    self.registry = other.registry.or(self.registry.take());
+    self.auth.extend(other.auth);
+    if has_credentials(&other.default_auth) {
+      self.default_auth = other.default_auth;
+    }
+    self.strict_ssl = other.strict_ssl.or(self.strict_ssl);
+    self.proxy = other.proxy.or(self.proxy.take());
+    self.https_proxy = other.https_proxy.or(self.https_proxy.take());
+    self.no_proxy = other.no_proxy.or(self.no_proxy.take());
+    self.cafile = other.cafile.or(self.cafile.take());
+    if !other.ca.is_empty() {
+      self.ca = other.ca;
+    }
+    self.cert = other.cert.or(self.cert.take());
+    self.key = other.key.or(self.key.take());
+  }
+
+  /// The default registry, `registry.npmjs.org` unless configured otherwise.
+  pub fn registry(&self) -> &str {
+    self.registry.as_deref().unwrap_or(DEFAULT_REGISTRY)
+  }
+
+  /// All configured credentials keyed by registry, with the unprefixed ones
+  /// attached to the default registry.
+  pub fn registry_auth(&self) -> HashMap<String, RegistryAuth> {
+    let mut registries = self.auth.clone();
+    if has_credentials(&self.default_auth) {
+      registries
+        .entry(self.registry().to_string())
+        .or_insert_with(|| self.default_auth.clone());
+    }
+    registries
+  }
+}
+
+fn has_credentials(auth: &RegistryAuth) -> bool {
+  auth.token.is_some() || auth.auth.is_some() || auth.username.is_some()
+}
+
+fn set_auth_field(auth: &mut RegistryAuth, field: &str, value: String) {
+  match field {
+    "_authToken" => auth.token = Some(value),
+    "_auth" => auth.auth = Some(value),
+    "username" => auth.username = Some(value),
+    // npm stores `_password` base64 encoded.
+    "_password" => {
+      auth.password = base64::engine::general_purpose::STANDARD
+        .decode(&value)
+        .ok()
+        .and_then(|password| String::from_utf8(password).ok())
+        .or(Some(value))
+    }
+    _ => {}
+  }
+}
+
+/// PEM values are written on one line with `\n` escapes.
+fn unescape_pem(value: &str) -> String {
+  value.replace("\\n", "\n")
+}
+
+fn unquote(value: &str) -> &str {
+  if value.len() >= 2
+    && ((value.starts_with('"') && value.ends_with('"'))
+      || (value.starts_with('\'') && value.ends_with('\'')))
+  {
+    &value[1..value.len() - 1]
+  } else {
+    value
+  }
+}
+
+/// Replaces `${VAR}` with the value of `VAR`, failing on undefined variables
+/// like npm does. `\${VAR}` is left as a literal `${VAR}`.
+fn replace_env(value: &str, env: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+  let mut result = String::with_capacity(value.len());
+  let mut rest = value;
+  while let Some(start) = rest.find("${") {
+    let Some(len) = rest[start..].find('}') else {
+      break;
+    };
+    if rest[..start].ends_with('\\') {
+      result.push_str(&rest[..start - 1]);
+      result.push_str(&rest[start..start + len + 1]);
+    } else {
+      let name = &rest[start + 2..start + len];
+      let replacement =
+        env(name).ok_or_else(|| format!("Failed to replace env in config: ${{{}}}", name))?;
+      result.push_str(&rest[..start]);
+      result.push_str(&replacement);
+    }
+    rest = &rest[start + len + 1..];
+  }
+  result.push_str(rest);
+  Ok(result)
+}
+
+#[test]
+fn parses_registries_auth_and_network_settings() {
+  let env = |name: &str| (name == "NPM_TOKEN").then(|| "s3cret".to_string());
+  let npmrc = Npmrc::parse(
+    r#"
+; a comment
+registry=https://npm.example.com/
+@company:registry = "https://npm.company.com/"
+//npm.company.com/:_authToken=${NPM_TOKEN}
+//npm.other.com/:username=bob
+//npm.other.com/:_password=aHVudGVyMg==
+strict-ssl=false
+https-proxy=http://proxy.local:3128
+cafile=/etc/ssl/company.pem
+ca[]="-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----"
+ca[]="-----BEGIN CERTIFICATE-----\nBBBB\n-----END CERTIFICATE-----"
+"#,
+    &env,
+  )
+  .unwrap();
+
+  assert_eq!(npmrc.registry(), "https://npm.example.com/");
+  assert_eq!(
+    npmrc.auth["//npm.company.com/"].token.as_deref(),
+    Some("s3cret")
+  );
+  assert_eq!(
+    npmrc.auth["//npm.other.com/"].username.as_deref(),
+    Some("bob")
+  );
+  assert_eq!(
+    npmrc.auth["//npm.other.com/"].password.as_deref(),
+    Some("hunter2")
+  );
+  assert_eq!(npmrc.strict_ssl, Some(false));
+  assert_eq!(
+    npmrc.https_proxy.as_deref(),
+    Some("http://proxy.local:3128")
+  );
+  assert_eq!(npmrc.cafile, Some(PathBuf::from("/etc/ssl/company.pem")));
+  assert_eq!(npmrc.ca.len(), 2);
+  assert_eq!(
+    npmrc.ca[0],
+    "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----"
+  );
+}
+
+#[test]
+fn fails_on_undefined_env_variables() {
+  let Err(error) = Npmrc::parse("//npm.example.com/:_authToken=${MISSING}", &|_| None) else {
+    panic!("expected an error");
+  };
+  assert!(error.contains("${MISSING}"), "{}", error);
+}
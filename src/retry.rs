@@ -0,0 +1,114 @@
+use reqwest::{header::HeaderMap, StatusCode};
+use std::{error::Error, fmt, time::Duration, time::SystemTime};
+
+use crate::FetchError;
+
+/// Retry settings, mirroring pnpm's `fetch-retries`, `fetch-retry-factor`,
+/// `fetch-retry-mintimeout` and `fetch-retry-maxtimeout`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+  pub retries: u32,
+  pub factor: f64,
+  pub min_timeout: Duration,
+  pub max_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      retries: 2,
+      factor: 10.0,
+      min_timeout: Duration::from_secs(10),
+      max_timeout: Duration::from_secs(60),
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// How long to wait before the given retry (1 for the first retry).
+  pub fn backoff(&self, retry: u32) -> Duration {
+    let exponent = retry.saturating_sub(1) as i32;
+    let millis = self.min_timeout.as_millis() as f64 * self.factor.powi(exponent);
+    Duration::from_millis(millis.min(self.max_timeout.as_millis() as f64) as u64)
+  }
+
+  /// The wait before the given retry, preferring the server's `Retry-After`
+  /// when it sent one. Neither may exceed `max_timeout`.
+  pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+    retry_after
+      .map(|delay| delay.min(self.max_timeout))
+      .unwrap_or_else(|| self.backoff(retry))
+  }
+}
+
+/// Statuses that are worth asking again for: rate limiting and server errors.
+pub fn is_retryable_status(status: StatusCode) -> bool {
+  status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Connection failures and timeouts are transient; anything else (a bad URL,
+/// a redirect loop) will fail the same way next time.
+pub fn is_retryable_error(error: &reqwest::Error) -> bool {
+  error.is_connect() || error.is_timeout() || error.is_request() || error.is_body()
+}
+
+/// Parses `Retry-After` as either delay-seconds or an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+  let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?;
+  if let Ok(seconds) = value.trim().parse::<u64>() {
+    return Some(Duration::from_secs(seconds));
+  }
+  let date = httpdate::parse_http_date(value).ok()?;
+  Some(
+    date
+      .duration_since(SystemTime::now())
+      .unwrap_or(Duration::ZERO),
+  )
+}
+
+/// The last error of a request that was given up on, with the number of
+/// attempts that were made.
+#[derive(Debug)]
+pub struct RetriesExhausted {
+  pub attempts: u32,
+  pub source: Box<FetchError>,
+}
+
+impl fmt::Display for RetriesExhausted {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let plural = if self.attempts == 1 { "" } else { "s" };
+    write!(
+      f,
+      "{} (after {} attempt{})",
+      self.source, self.attempts, plural
+    )
+  }
+}
+
+impl Error for RetriesExhausted {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    Some(self.source.as_ref())
+  }
+}
+
+#[test]
+fn backoff_grows_by_factor_and_is_capped() {
+  let policy = RetryPolicy::default();
+  assert_eq!(policy.backoff(1), Duration::from_secs(10));
+  assert_eq!(policy.backoff(2), Duration::from_secs(60));
+  assert_eq!(
+    policy.delay(1, Some(Duration::from_secs(3))),
+    Duration::from_secs(3)
+  );
+  assert_eq!(
+    policy.delay(1, Some(Duration::from_secs(3600))),
+    Duration::from_secs(60)
+  );
+}
+
+#[test]
+fn parses_retry_after_seconds() {
+  let mut headers = HeaderMap::new();
+  headers.insert(reqwest::header::RETRY_AFTER, "7".parse().unwrap());
+  assert_eq!(retry_after(&headers), Some(Duration::from_secs(7)));
+}
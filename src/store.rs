@@ -0,0 +1,90 @@
+use ssri::Integrity;
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+  content_path_from_hex, index_location, parse_integrity, ExtractedTarball, FileType, PackageFile,
+};
+
+/// Reads the files of a package that is already in the store, as recorded in
+/// its index by an earlier fetch (by us or by pnpm).
+///
+/// Returns `None` unless the index exists, is valid and every file it lists is
+/// still in the store with the recorded size, so that the caller can fall back
+/// to downloading the package.
+pub fn read_index(store_dir: &Path, integrity: &Integrity) -> Option<ExtractedTarball> {
+  let contents = std::fs::read(store_dir.join(index_location(integrity))).ok()?;
+  let index: serde_json::Value = serde_json::from_slice(&contents).ok()?;
+
+  let mut files = HashMap::new();
+  for (path, info) in index.get("files")?.as_object()? {
+    let file_integrity = parse_integrity(info.get("integrity")?.as_str()?).ok()?;
+    let mode = u32::try_from(info.get("mode")?.as_u64()?).ok()?;
+    let size = info.get("size")?.as_i64()?;
+    let location = store_dir.join(content_path_from_hex(
+      FileType::from_mode(mode),
+      &file_integrity.to_hex().1,
+    ));
+    let metadata = std::fs::metadata(&location).ok()?;
+    if !metadata.is_file() || metadata.len() != size as u64 {
+      return None;
+    }
+    files.insert(
+      path.clone(),
+      PackageFile {
+        location: location.to_string_lossy().into_owned(),
+        integrity: file_integrity.to_string(),
+        mode,
+        size,
+        checked_at: info
+          .get("checkedAt")
+          .and_then(|checked_at| checked_at.as_i64())
+          .unwrap_or_default(),
+      },
+    );
+  }
+
+  Some(ExtractedTarball {
+    files,
+    integrity: Some(integrity.to_string()),
+    ..Default::default()
+  })
+}
+
+#[test]
+fn read_index_requires_every_file_to_be_in_the_store() {
+  let store = tempfile::tempdir().unwrap();
+  let integrity = Integrity::from(b"tarball");
+  let data = crate::tar_with_files(&[
+    ("package/index.js", b"module.exports = 1\n"),
+    ("package/README.md", b"# foo\n"),
+  ]);
+  let extracted = crate::extract_tarball(
+    store.path(),
+    &index_location(&integrity),
+    &data[..],
+    &Default::default(),
+  )
+  .unwrap();
+
+  let found = read_index(store.path(), &integrity).unwrap();
+  assert_eq!(found.files, extracted.files);
+
+  std::fs::remove_file(&extracted.files["README.md"].location).unwrap();
+  assert!(read_index(store.path(), &integrity).is_none());
+}
+
+#[test]
+fn read_index_ignores_invalid_indexes() {
+  let store = tempfile::tempdir().unwrap();
+  let integrity = Integrity::from(b"tarball");
+  let location = store.path().join(index_location(&integrity));
+  std::fs::create_dir_all(location.parent().unwrap()).unwrap();
+  for index in [
+    r#"{"files":{"index.js":{"integrity":"sha512-bogus","mode":420,"size":2}}}"#,
+    r#"{"files":{"index.js":{"mode":420,"size":2}}}"#,
+    "not json",
+  ] {
+    std::fs::write(&location, index).unwrap();
+    assert!(read_index(store.path(), &integrity).is_none(), "{index}");
+  }
+}
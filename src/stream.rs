@@ -0,0 +1,316 @@
+//! Downloading and extracting at the same time: the response body is hashed,
+//! inflated and unpacked into the store as it arrives, so that no more than a
+//! few chunks of the tarball are held in memory.
+
+use bytes::{Buf, Bytes};
+use reqwest::header::CONTENT_TYPE;
+use ssri::{Algorithm, Integrity, IntegrityOpts};
+use std::{
+  io::{self, Read},
+  path::{Path, PathBuf},
+};
+use tokio::{sync::mpsc, task};
+
+use crate::{
+  format, gzip, index_location, next_chunk, parse_integrity, send_checked, timeout::with_timeout,
+  write_index, write_to_cas, ArchiveFormat, AttemptError, DecompressError, ExtractOptions,
+  ExtractedTarball, FetchError, HttpSettings, IntegrityError, Limit, LimitExceeded, TimeoutPhase,
+};
+
+/// Chunks buffered between the download and the extraction.
+const CHANNEL_CAPACITY: usize = 16;
+
+/// Hashes everything that is read through it.
+pub struct HashingReader<R> {
+  inner: R,
+  hasher: IntegrityOpts,
+  size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+  pub fn new(inner: R, algorithm: Algorithm) -> Self {
+    HashingReader {
+      inner,
+      hasher: IntegrityOpts::new().algorithm(algorithm),
+      size: 0,
+    }
+  }
+
+  /// The hash and length of everything read so far.
+  pub fn finish(self) -> (Integrity, u64) {
+    (self.hasher.result(), self.size)
+  }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    self.hasher.input(&buf[..read]);
+    self.size += read as u64;
+    Ok(read)
+  }
+}
+
+/// Reads the chunks sent by the download task, blocking until they arrive.
+/// The download sends `None` after the last chunk, so that a download that
+/// stopped midway, and dropped its sender, reads as truncated.
+struct ChannelReader {
+  chunks: mpsc::Receiver<io::Result<Option<Bytes>>>,
+  current: Bytes,
+  finished: bool,
+}
+
+impl Read for ChannelReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.current.is_empty() && !self.finished {
+      match self.chunks.blocking_recv() {
+        Some(Ok(Some(chunk))) => self.current = chunk,
+        Some(Ok(None)) => self.finished = true,
+        Some(Err(error)) => return Err(error),
+        None => {
+          let message = "download interrupted";
+          return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+        }
+      }
+    }
+    let read = buf.len().min(self.current.len());
+    buf[..read].copy_from_slice(&self.current[..read]);
+    self.current.advance(read);
+    Ok(read)
+  }
+}
+
+/// Tags the errors of the decoder, see `FetchError::from_archive`. Limits
+/// exceeded while reading the compressed data are passed on as is.
+struct Decompressing<R> {
+  decoder: R,
+  format: ArchiveFormat,
+}
+
+impl<R: Read> Read for Decompressing<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.decoder.read(buf).map_err(|error| {
+      if error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<LimitExceeded>())
+      {
+        return error;
+      }
+      let error = match self.format {
+        ArchiveFormat::Gzip => gzip::truncated_member(error),
+        _ => error,
+      };
+      io::Error::new(error.kind(), DecompressError(error))
+    })
+  }
+}
+
+/// Unpacks a package archive into the store, a gzipped tarball unless its
+/// first bytes or `hint` say otherwise. The index is only written once the
+/// downloaded bytes turned out to match `expected_checksum`.
+pub fn extract_verified(
+  data: impl Read,
+  store_dir: &Path,
+  expected_checksum: Option<&str>,
+  hint: Option<ArchiveFormat>,
+  options: &ExtractOptions,
+) -> Result<ExtractedTarball, FetchError> {
+  let expected = match expected_checksum {
+    Some(value) => Some((value, parse_integrity(value)?)),
+    None => None,
+  };
+  let algorithm = expected
+    .as_ref()
+    .map_or(Algorithm::Sha512, |(_, expected)| expected.pick_algorithm());
+  let mut compressed = options
+    .limits
+    .reader(Limit::CompressedBytes, HashingReader::new(data, algorithm));
+
+  let head =
+    format::read_head(&mut compressed, format::MAGIC_LEN).map_err(FetchError::from_archive)?;
+  let format = ArchiveFormat::detect(&head, hint);
+  if format == ArchiveFormat::Gzip {
+    gzip::check_header(&head)?;
+  }
+  let data = Read::chain(&head[..], &mut compressed);
+  let extracted = if format == ArchiveFormat::Zip {
+    format::write_zip_to_cas(store_dir, data, options)?
+  } else {
+    let decoder = Decompressing {
+      decoder: format.decoder(data).map_err(FetchError::decompress)?,
+      format,
+    };
+    let mut unpacked = options.limits.reader(Limit::UnpackedBytes, decoder);
+    let extracted = write_to_cas(store_dir, &mut unpacked, options)?;
+    // The tar end-of-archive marker may come before the end of the compressed
+    // stream, whose checksum is only verified once it's read to the end.
+    // Whatever follows the marker still counts as unpacked.
+    io::copy(&mut unpacked, &mut io::sink()).map_err(FetchError::from_archive)?;
+    extracted
+  };
+  io::copy(&mut compressed, &mut io::sink()).map_err(FetchError::from_archive)?;
+
+  let (actual, size) = compressed.into_inner().finish();
+  let integrity = match expected {
+    Some((value, expected)) if expected.matches(&actual).is_none() => {
+      return Err(
+        IntegrityError {
+          url: None,
+          expected: value.to_string(),
+          actual: actual.to_string(),
+          size,
+          attempt: None,
+        }
+        .into(),
+      );
+    }
+    Some((_, expected)) => expected,
+    // Nothing to verify against, the caller records what we got.
+    None => actual,
+  };
+  write_index(store_dir, &index_location(&integrity), &extracted.files)?;
+  Ok(ExtractedTarball {
+    integrity: Some(integrity.to_string()),
+    ..extracted
+  })
+}
+
+/// A single attempt at downloading a tarball and extracting it on the fly,
+/// taking at most the total timeout from connecting to the last byte.
+///
+/// The extraction is awaited even when the download fails or times out, so
+/// that it never goes on writing into the store after the attempt is over.
+pub async fn fetch_and_extract_once(
+  http: &HttpSettings,
+  url: &str,
+  store_dir: PathBuf,
+  expected_checksum: Option<String>,
+  options: ExtractOptions,
+) -> Result<ExtractedTarball, AttemptError> {
+  let mut extraction = None;
+  let download = with_timeout(http.timeouts.total, url, TimeoutPhase::Total, async {
+    Ok(
+      download_into(
+        http,
+        url,
+        &mut extraction,
+        store_dir,
+        expected_checksum,
+        options,
+      )
+      .await,
+    )
+  })
+  .await;
+  let extracted = match extraction {
+    Some(extraction) => Some(
+      extraction
+        .await
+        .map_err(|error| (FetchError::from(error), None))?,
+    ),
+    None => None,
+  };
+  // A failed download explains a failed extraction, not the other way round.
+  download
+    .map_err(|error| (error, None))
+    .and_then(|download| download)?;
+  let extracted = extracted.expect("a complete download was extracted");
+  extracted.map_err(|error| match error {
+    FetchError::Integrity(mut error) => {
+      error.url = Some(url.to_string());
+      (error.into(), None)
+    }
+    error => (error, None),
+  })
+}
+
+/// Downloads the tarball, passing its chunks on to an extraction that is
+/// started once the response turned out to be worth extracting.
+async fn download_into(
+  http: &HttpSettings,
+  url: &str,
+  extraction: &mut Option<task::JoinHandle<Result<ExtractedTarball, FetchError>>>,
+  store_dir: PathBuf,
+  expected_checksum: Option<String>,
+  options: ExtractOptions,
+) -> Result<(), AttemptError> {
+  let mut res = send_checked(http, url).await?;
+  let hint = res
+    .headers()
+    .get(CONTENT_TYPE)
+    .and_then(|value| value.to_str().ok())
+    .and_then(ArchiveFormat::from_content_type)
+    .or_else(|| ArchiveFormat::from_extension(res.url().path()));
+  if let Some(length) = res.content_length() {
+    // Fail before downloading anything when the registry says it's too much.
+    options
+      .limits
+      .check(Limit::CompressedBytes, length, None)
+      .map_err(|error| (error.into(), None))?;
+  }
+
+  let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+  *extraction = Some(task::spawn_blocking(move || {
+    let reader = ChannelReader {
+      chunks: receiver,
+      current: Bytes::new(),
+      finished: false,
+    };
+    extract_verified(
+      reader,
+      &store_dir,
+      expected_checksum.as_deref(),
+      hint,
+      &options,
+    )
+  }));
+
+  while let Some(chunk) = next_chunk(&mut res, &http.timeouts, url)
+    .await
+    .map_err(|error| (error, None))?
+  {
+    if sender.send(Ok(Some(chunk))).await.is_err() {
+      // The extraction gave up, its error says why.
+      return Ok(());
+    }
+  }
+  let _ = sender.send(Ok(None)).await;
+  Ok(())
+}
+
+#[test]
+fn extract_verified_accepts_any_supported_integrity() {
+  let (tarball, _) = crate::test_tarball();
+  let hash = |algorithm| {
+    IntegrityOpts::new()
+      .algorithm(algorithm)
+      .chain(&tarball)
+      .result()
+  };
+  let extract = |expected: &str| {
+    let store = tempfile::tempdir().unwrap();
+    extract_verified(
+      &tarball[..],
+      store.path(),
+      Some(expected),
+      None,
+      &Default::default(),
+    )
+  };
+
+  for expected in [
+    hash(Algorithm::Sha256).to_string(),
+    format!("{} sha512-bogus", hash(Algorithm::Sha512)),
+    hash(Algorithm::Sha1).to_hex().1,
+  ] {
+    assert!(extract(&expected).is_ok(), "{expected}");
+  }
+  assert!(matches!(
+    extract("sha384-OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb"),
+    Err(FetchError::Integrity(error)) if error.actual.starts_with("sha384-")
+  ));
+  assert!(matches!(
+    extract("sha384-bogus"),
+    Err(FetchError::InvalidInput(_))
+  ));
+}
@@ -0,0 +1,89 @@
+//! A minimal HTTP/1.1 server for tests. Every connection gets the next canned
+//! response and is closed afterwards.
+
+use std::sync::{Arc, Mutex};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+};
+
+pub struct TestServer {
+  pub url: String,
+  requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestServer {
+  /// The head (request line and headers) of every request received so far.
+  pub fn requests(&self) -> Vec<String> {
+    self.requests.lock().unwrap().clone()
+  }
+}
+
+pub fn response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+  let mut head = format!(
+    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n",
+    status,
+    body.len()
+  );
+  for (name, value) in headers {
+    head.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  head.push_str("\r\n");
+  let mut response = head.into_bytes();
+  response.extend_from_slice(body);
+  response
+}
+
+pub async fn serve(responses: Vec<Vec<u8>>) -> TestServer {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  tokio::spawn(async move {
+    for response in responses {
+      let (mut socket, _) = listener.accept().await.unwrap();
+      let head = read_head(&mut socket).await;
+      received.lock().unwrap().push(head);
+      socket.write_all(&response).await.unwrap();
+      socket.shutdown().await.ok();
+    }
+  });
+  TestServer { url, requests }
+}
+
+/// Sends every connection `start`, which may be nothing or a response cut
+/// short, and then keeps it open without sending anything more.
+pub async fn serve_stalled(start: Vec<u8>) -> TestServer {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let url = format!("http://{}", listener.local_addr().unwrap());
+  let requests = Arc::new(Mutex::new(Vec::new()));
+  let received = requests.clone();
+  tokio::spawn(async move {
+    let mut sockets = Vec::new();
+    while let Ok((mut socket, _)) = listener.accept().await {
+      let head = read_head(&mut socket).await;
+      received.lock().unwrap().push(head);
+      socket.write_all(&start).await.unwrap();
+      sockets.push(socket);
+    }
+  });
+  TestServer { url, requests }
+}
+
+/// Accepts connections but never answers them.
+pub async fn stalled_server() -> TestServer {
+  serve_stalled(Vec::new()).await
+}
+
+async fn read_head(socket: &mut TcpStream) -> String {
+  let mut head = Vec::new();
+  let mut buf = [0; 1024];
+  while !head.ends_with(b"\r\n\r\n") {
+    let n = socket.read(&mut buf).await.unwrap();
+    if n == 0 {
+      break;
+    }
+    head.extend_from_slice(&buf[..n]);
+  }
+  String::from_utf8_lossy(&head).into_owned()
+}
@@ -0,0 +1,84 @@
+use std::{error::Error, fmt, future::Future, time::Duration};
+
+/// Limits on how long a single download attempt may take.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Timeouts {
+  /// Establishing the TCP (and TLS) connection.
+  pub connect: Option<Duration>,
+  /// Waiting for the response headers, and then for each next chunk of the
+  /// response body.
+  pub read: Option<Duration>,
+  /// The whole attempt, from connecting to the last byte. pnpm's `fetch-timeout`.
+  pub total: Option<Duration>,
+}
+
+impl Timeouts {
+  /// pnpm gives up on a request after 60 seconds by default.
+  pub const DEFAULT_TOTAL: Duration = Duration::from_secs(60);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutPhase {
+  Connect,
+  Read,
+  Total,
+}
+
+impl TimeoutPhase {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      TimeoutPhase::Connect => "connect",
+      TimeoutPhase::Read => "read",
+      TimeoutPhase::Total => "total",
+    }
+  }
+}
+
+/// A download attempt ran out of time.
+#[derive(Debug)]
+pub struct TimeoutError {
+  pub url: String,
+  pub phase: TimeoutPhase,
+  pub limit: Option<Duration>,
+}
+
+impl fmt::Display for TimeoutError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let what = match self.phase {
+      TimeoutPhase::Connect => "connecting",
+      TimeoutPhase::Read => "waiting for data",
+      TimeoutPhase::Total => "the request",
+    };
+    write!(f, "GET {}: timed out on {}", self.url, what)?;
+    if let Some(limit) = self.limit {
+      write!(f, " after {}ms", limit.as_millis())?;
+    }
+    Ok(())
+  }
+}
+
+impl Error for TimeoutError {}
+
+/// Runs `future` to completion, or fails once `limit` has passed.
+pub async fn with_timeout<T, E: From<TimeoutError>>(
+  limit: Option<Duration>,
+  url: &str,
+  phase: TimeoutPhase,
+  future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+  match limit {
+    Some(limit) => tokio::time::timeout(limit, future)
+      .await
+      .unwrap_or_else(|_| {
+        Err(
+          TimeoutError {
+            url: url.to_string(),
+            phase,
+            limit: Some(limit),
+          }
+          .into(),
+        )
+      }),
+    None => future.await,
+  }
+}
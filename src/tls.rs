@@ -0,0 +1,110 @@
+use reqwest::{Certificate, ClientBuilder, Identity};
+use std::{io, path::PathBuf};
+
+use crate::{FetchError, Npmrc};
+
+/// Which servers to trust and how to identify ourselves to them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+  /// When false, any certificate is accepted (`strict-ssl=false`).
+  pub strict_ssl: bool,
+  /// PEM encoded certificates trusted in addition to the built-in roots.
+  pub ca: Vec<String>,
+  /// A file of PEM encoded certificates trusted in addition to the built-in roots.
+  pub cafile: Option<PathBuf>,
+  /// PEM encoded client certificate and private key, for registries that
+  /// require mutual TLS.
+  pub cert: Option<String>,
+  pub key: Option<String>,
+}
+
+impl Default for TlsConfig {
+  fn default() -> Self {
+    TlsConfig {
+      strict_ssl: true,
+      ca: Vec::new(),
+      cafile: None,
+      cert: None,
+      key: None,
+    }
+  }
+}
+
+impl TlsConfig {
+  pub fn from_npmrc(npmrc: &Npmrc) -> Self {
+    TlsConfig {
+      strict_ssl: npmrc.strict_ssl.unwrap_or(true),
+      ca: npmrc.ca.clone(),
+      cafile: npmrc.cafile.clone(),
+      cert: npmrc.cert.clone(),
+      key: npmrc.key.clone(),
+    }
+  }
+
+  pub fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, FetchError> {
+    if !self.strict_ssl {
+      builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    let mut bundles: Vec<Vec<u8>> = self.ca.iter().map(|ca| ca.as_bytes().to_vec()).collect();
+    if let Some(cafile) = &self.cafile {
+      bundles.push(std::fs::read(cafile).map_err(|error| {
+        FetchError::Io(io::Error::new(
+          error.kind(),
+          format!("Failed to read cafile {}: {}", cafile.display(), error),
+        ))
+      })?);
+    }
+    for bundle in bundles {
+      let certificates = Certificate::from_pem_bundle(&bundle)?;
+      if certificates.is_empty() {
+        return Err(FetchError::InvalidInput(
+          "No PEM certificates found in the CA bundle".to_string(),
+        ));
+      }
+      for certificate in certificates {
+        builder = builder.add_root_certificate(certificate);
+      }
+    }
+
+    match (&self.cert, &self.key) {
+      (Some(cert), Some(key)) => {
+        let pem = format!("{}\n{}", cert.trim_end(), key);
+        builder = builder.identity(Identity::from_pem(pem.as_bytes())?);
+      }
+      (None, None) => {}
+      _ => {
+        return Err(FetchError::InvalidInput(
+          "A client certificate needs both `cert` and `key`".to_string(),
+        ))
+      }
+    }
+
+    Ok(builder)
+  }
+}
+
+#[test]
+fn rejects_incomplete_tls_settings() {
+  let missing_key = TlsConfig {
+    cert: Some("-----BEGIN CERTIFICATE-----".to_string()),
+    ..Default::default()
+  };
+  assert!(missing_key.apply(reqwest::Client::builder()).is_err());
+
+  let empty_ca = TlsConfig {
+    ca: vec!["not a certificate".to_string()],
+    ..Default::default()
+  };
+  assert!(empty_ca.apply(reqwest::Client::builder()).is_err());
+
+  let missing_cafile = TlsConfig {
+    cafile: Some(PathBuf::from("/nonexistent/ca.pem")),
+    ..Default::default()
+  };
+  let error = missing_cafile
+    .apply(reqwest::Client::builder())
+    .err()
+    .unwrap();
+  assert!(error.to_string().contains("/nonexistent/ca.pem"));
+}